@@ -1,23 +1,808 @@
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufWriter, IsTerminal, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde_json::json;
+
+use tundraix_src::chunk::Chunk;
+use tundraix_src::codes;
 use tundraix_src::compiler::Parser;
-use tundraix_src::vm::VM;
-use tundraix_src::error::ErrorResult;
+use tundraix_src::disassembler::disassemble_chunk;
+use tundraix_src::format::format_source;
+use tundraix_src::limits::Limits;
+use tundraix_src::optimize::hoist_loop_globals;
+use tundraix_src::profiler::LineProfile;
+use tundraix_src::sandbox::SandboxProfile;
+use tundraix_src::vm::{HeapStats, VM};
+use tundraix_src::error::{Error, ErrorResult, RuntimeError, RuntimeResult};
+use tundraix_src::exec::ExecDecision;
+use tundraix_src::output::OutputSink;
+use tundraix_src::session::Session;
+use tundraix_src::value::Value;
+
+const DEMO_SOURCE: &str = r#"
+    var a = 3;
+    var b = 4 + 2 * a;
+    print b;
+    b = 4;
+    print b;
+"#;
+
+const REPL_HELP: &str = "\
+:vars         list the globals defined so far
+:dis          disassemble the last compiled input
+:load FILE    run a script file in this session
+:clear        start over with a fresh VM
+:help         show this list
+Anything else is run as source. Ctrl-D exits.
+";
+
+const PROFILE_LINES: usize = 10;
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct Options {
+    disassemble: bool,
+    optimize: bool,
+    json: bool,
+    time: bool,
+    stats: bool,
+    profile: bool,
+    watch: bool,
+    check: bool,
+    format: bool,
+    repl: bool,
+    unbuffered: bool,
+    dump_on_error: bool,
+    history: bool,
+    paths: Vec<String>,
+    code: Option<String>,
+    script_args: Vec<String>,
+    sandbox: Option<SandboxProfile>,
+    fs_root: Option<String>,
+    env_allowlist: Option<Vec<String>>,
+    allow_exec: bool,
+    test_dir: Option<String>,
+    explain: Option<String>
+}
+
+#[derive(Default)]
+struct RunStats {
+    compile: Duration,
+    run: Duration,
+    instructions: u64,
+    heap: HeapStats,
+    profile: Vec<LineProfile>
+}
+
+// Flushes after every line, or after every write with '--unbuffered',
+// so progress printed by long running scripts shows up as it happens.
+struct StdoutSink {
+    out: BufWriter<Stdout>,
+    unbuffered: bool
+}
+
+impl StdoutSink {
+    fn new(unbuffered: bool) -> Self {
+        Self {
+            out: BufWriter::new(io::stdout()),
+            unbuffered
+        }
+    }
+}
+
+// Collects what a test script prints so it can be compared with the
+// script's `// expect:` comments.
+struct CaptureSink(Rc<RefCell<String>>);
+
+impl OutputSink for CaptureSink {
+    fn write(&mut self, text: &str) -> ErrorResult<()> {
+        self.0.borrow_mut().push_str(text);
+        Ok(())
+    }
+}
+
+impl OutputSink for StdoutSink {
+    fn write(&mut self, text: &str) -> ErrorResult<()> {
+        self.out.write_all(text.as_bytes())
+            .map_err(|error| format!("Could not write output: {}", error))?;
+
+        if self.unbuffered || text.contains('\n') {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> ErrorResult<()> {
+        self.out.flush()
+            .map_err(|error| format!("Could not write output: {}", error))
+    }
+}
+
+fn parse_args() -> ErrorResult<Options> {
+    let mut options = Options::default();
+    let mut args = env::args().skip(1).peekable();
+
+    match args.peek().map(String::as_str) {
+        Some("fmt") => options.format = true,
+        Some("repl") => options.repl = true,
+        _ => {}
+    }
+
+    if options.format || options.repl {
+        args.next();
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dis" => options.disassemble = true,
+            "--optimize" => options.optimize = true,
+            "--json" => options.json = true,
+            "--time" => options.time = true,
+            "--stats" => options.stats = true,
+            "--profile" => options.profile = true,
+            "--watch" => options.watch = true,
+            "--check" => options.check = true,
+            "--unbuffered" => options.unbuffered = true,
+            "--dump-on-error" => options.dump_on_error = true,
+            "--history" => options.history = true,
+            "--sandbox" => match args.next().as_deref() {
+                Some("strict") => options.sandbox = Some(SandboxProfile::strict()),
+                Some("relaxed") => options.sandbox = Some(SandboxProfile::relaxed()),
+                _ => return Err(Error::from("Expected 'strict' or 'relaxed' after '--sandbox'."))
+            },
+            "--allow-fs" => match args.next() {
+                Some(root) => options.fs_root = Some(root),
+                None => return Err(Error::from("Expected a directory after '--allow-fs'."))
+            },
+            "--allow-exec" => options.allow_exec = true,
+            "--allow-env" => match args.next() {
+                Some(names) => options.env_allowlist = Some(names.split(',').map(str::to_string).collect()),
+                None => return Err(Error::from("Expected variable names after '--allow-env'."))
+            },
+            "--explain" => match args.next() {
+                Some(code) => options.explain = Some(code),
+                None => return Err(Error::from("Expected an error code after '--explain'."))
+            },
+            "--test" => match args.next() {
+                Some(dir) => options.test_dir = Some(dir),
+                None => return Err(Error::from("Expected a directory after '--test'."))
+            },
+            "--" => {
+                options.script_args = args.by_ref().collect();
+            },
+            "-e" => match args.next() {
+                Some(code) => options.code = Some(code),
+                None => return Err(Error::from("Expected code after '-e'."))
+            },
+            _ if arg.starts_with('-') => return Err(format!("Unknown option '{}'.", arg)),
+            _ => options.paths.push(arg)
+        }
+    }
+
+    // Sandboxed scripts never get to run commands.
+    if options.allow_exec && options.sandbox.is_some() {
+        return Err(Error::from("'--allow-exec' can't be combined with '--sandbox'."));
+    }
+
+    if options.explain.is_some() {
+        if !options.paths.is_empty() || options.code.is_some() || options.test_dir.is_some() {
+            return Err(Error::from("'--explain' can't be combined with a script file, '-e' or '--test'."));
+        }
+
+        return Ok(options)
+    }
+
+    if options.test_dir.is_some() {
+        if !options.paths.is_empty() || options.code.is_some() {
+            return Err(Error::from("'--test' can't be combined with a script file or '-e'."));
+        }
+
+        return Ok(options)
+    }
+
+    if options.format {
+        if options.paths.len() != 1 || options.code.is_some() {
+            return Err(Error::from("'fmt' needs exactly one script file."));
+        }
+
+        return Ok(options)
+    }
+
+    if options.repl {
+        if !options.paths.is_empty() || options.code.is_some() || options.watch || options.json {
+            return Err(Error::from("'repl' can't be combined with a script file, '-e', '--watch' or '--json'."));
+        }
+
+        return Ok(options)
+    }
+
+    if options.check {
+        if options.paths.is_empty() {
+            return Err(Error::from("'--check' needs at least one script file."));
+        }
+
+        return Ok(options)
+    }
+
+    if options.paths.len() > 1 {
+        return Err(Error::from("Only one script file can be given."));
+    }
+
+    if !options.paths.is_empty() && options.code.is_some() {
+        return Err(Error::from("A script file and '-e' can't be used together."));
+    }
+
+    if options.watch && options.paths.is_empty() {
+        return Err(Error::from("'--watch' needs a script file."));
+    }
+
+    if options.json && options.watch {
+        return Err(Error::from("'--json' can't be combined with '--watch'."));
+    }
+
+    Ok(options)
+}
+
+fn read_source(options: &Options) -> ErrorResult<String> {
+    if let Some(code) = &options.code {
+        return Ok(code.clone())
+    }
+
+    match options.paths.first() {
+        Some(path) => read_file(path),
+        None => Ok(DEMO_SOURCE.to_string())
+    }
+}
+
+fn read_file(path: &str) -> ErrorResult<String> {
+    fs::read_to_string(path)
+        .map_err(|error| format!("Could not read '{}': {}", path, error))
+}
+
+fn compile(options: &Options, source: &str, stats: &mut RunStats) -> ErrorResult<Chunk> {
+    let limits = options.sandbox.map_or_else(Limits::default, |profile| profile.limits);
+
+    let start = Instant::now();
+    let mut parser = Parser::with_limits(source, limits).with_debug_info(options.disassemble);
+    let mut chunk = parser.parse()?;
+    if options.optimize {
+        hoist_loop_globals(&mut chunk);
+    }
+    stats.compile = start.elapsed();
+    Ok(chunk)
+}
+
+fn execute(options: &Options, source: &str, stats: &mut RunStats) -> ErrorResult<()> {
+    let chunk = compile(options, source, stats)?;
+
+    if options.disassemble {
+        if options.json {
+            let instructions: Vec<_> = chunk.instructions().collect();
+            let json = serde_json::to_string_pretty(&instructions)
+                .map_err(|error| error.to_string())?;
+            println!("{}", json);
+        } else {
+            print!("{}", disassemble_chunk(&chunk));
+        }
+
+        return Ok(())
+    }
+
+    let mut vm = new_vm(options, StdoutSink::new(options.unbuffered))?;
+    run_chunk(options, &mut vm, chunk, stats)?;
 
-fn print_fn(text: String) -> ErrorResult<()> {
-    print!("{}", text);
     Ok(())
 }
 
-fn main() -> ErrorResult<()> {
-    let mut parser = Parser::new(r#"
-        var a = 3;
-        var b = 4 + 2 * a;
-        print b;
-        b = 4;
-        print b;
-    "#);
+fn new_vm<O: OutputSink + 'static>(options: &Options, output: O) -> ErrorResult<VM> {
+    let mut vm = VM::with_output(output);
+    if let Some(profile) = options.sandbox {
+        vm = vm.sandboxed(profile);
+    }
+
+    vm.set_history(options.history || options.dump_on_error);
+    vm.set_args(options.script_args.clone());
+    vm.set_log_fn(|level, message| eprintln!("[{}] {}", level, message));
+    vm.enable_profiler(options.profile);
+
+    if let Some(root) = &options.fs_root {
+        vm.allow_fs(root)?;
+    }
+
+    if let Some(allowlist) = &options.env_allowlist {
+        vm.allow_env(allowlist.clone());
+    }
+
+    if options.allow_exec {
+        vm.allow_exec(|_, _| ExecDecision::Run);
+    }
+
+    Ok(vm)
+}
+
+fn run_chunk(options: &Options, vm: &mut VM, chunk: Chunk, stats: &mut RunStats) -> RuntimeResult<()> {
+    let start = Instant::now();
+    let result = vm.interpret(chunk);
+    stats.run = start.elapsed();
+    stats.instructions = vm.instruction_count();
+    stats.heap = vm.heap_stats();
+    stats.profile = vm.profile();
+
+    if result.is_err() && options.dump_on_error {
+        eprint!("{}", vm.dump_state());
+    }
+
+    result
+}
+
+fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// Splits a "[line N] Error: message" diagnostic into its parts. The
+// compiler doesn't track columns, so "column" is always null.
+fn diagnostic(file: Option<&String>, error: &str) -> serde_json::Value {
+    let parsed = error.strip_prefix("[line ")
+        .and_then(|rest| rest.split_once("] Error"))
+        .and_then(|(line, rest)| Some((line.parse::<usize>().ok()?, rest.split_once(": ")?)));
+
+    let (line, code, message) = match parsed {
+        Some((line, (code, message))) => (Some(line), code.strip_prefix(' '), message),
+        None => (None, None, error)
+    };
+
+    json!({
+        "file": file,
+        "line": line,
+        "column": null,
+        "code": code,
+        "message": message
+    })
+}
+
+fn runtime_error_json(error: &RuntimeError) -> serde_json::Value {
+    let trace: Vec<_> = error.trace.iter()
+        .map(|frame| json!({
+            "function": frame.function,
+            "call_line": frame.call_line
+        }))
+        .collect();
+
+    let history: Vec<_> = error.history.iter()
+        .map(|entry| json!({
+            "offset": entry.offset,
+            "instruction": entry.name,
+            "line": entry.line
+        }))
+        .collect();
+
+    json!({
+        "line": error.line,
+        "code": error.code,
+        "message": error.message,
+        "trace": trace,
+        "history": history
+    })
+}
+
+// Runs the script like `run` but reports everything as one JSON
+// document on stdout, with what the script printed captured in
+// "output" instead of written as it goes. Returns whether it succeeded.
+fn run_json(options: &Options) -> bool {
+    let file = options.paths.first();
+    let output = Rc::new(RefCell::new(String::new()));
+    let mut stats = RunStats::default();
+    let mut errors = Vec::new();
+    let mut runtime_error = None;
+
+    let chunk = read_source(options).and_then(|source| compile(options, &source, &mut stats));
+    let vm = new_vm(options, CaptureSink(output.clone()));
+
+    match (chunk, vm) {
+        (Ok(chunk), Ok(mut vm)) => {
+            if let Err(error) = run_chunk(options, &mut vm, chunk, &mut stats) {
+                runtime_error = Some(runtime_error_json(&error));
+            }
+        },
+        (chunk, vm) => {
+            // Compile errors are reported one per line.
+            for error in chunk.err().iter().chain(vm.err().iter()) {
+                errors.extend(error.lines().map(|line| diagnostic(file, line)));
+            }
+        }
+    }
+
+    let ok = errors.is_empty() && runtime_error.is_none();
+    let output = output.borrow();
+
+    let mut document = json!({
+        "file": file,
+        "ok": ok,
+        "exit_status": if ok { 0 } else { 1 },
+        "output": *output,
+        "errors": errors,
+        "runtime_error": runtime_error
+    });
+
+    if options.time {
+        document["timing"] = json!({
+            "compile_ms": as_millis(stats.compile),
+            "run_ms": as_millis(stats.run),
+            "instructions": stats.instructions
+        });
+    }
+
+    println!("{}", document);
+    ok
+}
 
-    let chunk = parser.parse()?;
-    let mut vm = VM::new(print_fn);
+// Reads, compiles and runs the script described by the options with
+// a fresh VM, so nothing carries over between calls.
+fn run(options: &Options) -> ErrorResult<()> {
+    let source = read_source(options)?;
+
+    let mut stats = RunStats::default();
+    let result = execute(options, &source, &mut stats);
+
+    if options.time {
+        eprintln!(
+            "compile: {:.1}ms, run: {:.1}ms, instructions: {}",
+            as_millis(stats.compile),
+            as_millis(stats.run),
+            stats.instructions
+        );
+    }
+
+    if options.stats {
+        eprintln!("heap: strings: {}, string bytes: {}", stats.heap.strings, stats.heap.string_bytes);
+    }
+
+    if options.profile {
+        print_profile(&source, &stats.profile);
+    }
+
+    result
+}
+
+// The hottest lines with their source text, most expensive first.
+fn print_profile(source: &str, profile: &[LineProfile]) {
+    let lines: Vec<_> = source.lines().collect();
+
+    eprintln!("{:>6} {:>12} {:>10}  source", "line", "instructions", "time");
+
+    for entry in profile.iter().take(PROFILE_LINES) {
+        let text = entry.line.checked_sub(1)
+            .and_then(|index| lines.get(index))
+            .map_or("", |text| text.trim());
+
+        eprintln!(
+            "{:>6} {:>12} {:>8.2}ms  {}",
+            entry.line,
+            entry.instructions,
+            as_millis(entry.approx_time),
+            text
+        );
+    }
+}
+
+// An interactive session: every line runs in the same VM, so globals
+// carry over between lines. Lines starting with ':' are commands.
+struct Repl<'a> {
+    options: &'a Options,
+    vm: VM,
+    last_chunk: Option<Chunk>
+}
+
+impl<'a> Repl<'a> {
+    fn new(options: &'a Options) -> ErrorResult<Self> {
+        Ok(Self {
+            options,
+            vm: new_vm(options, StdoutSink::new(options.unbuffered))?,
+            last_chunk: None
+        })
+    }
+
+    fn run_lines(&mut self, input: impl BufRead) -> ErrorResult<()> {
+        let interactive = io::stdin().is_terminal();
+
+        Self::prompt(interactive)?;
+
+        for line in input.lines() {
+            let line = line.map_err(|error| format!("Could not read input: {}", error))?;
+
+            if let Err(error) = self.line(&line) {
+                eprintln!("{}", error);
+            }
+
+            Self::prompt(interactive)?;
+        }
+
+        Ok(())
+    }
+
+    fn prompt(interactive: bool) -> ErrorResult<()> {
+        if interactive {
+            print!("> ");
+            io::stdout().flush().map_err(|error| format!("Could not write output: {}", error))?;
+        }
+
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> ErrorResult<()> {
+        let line = line.trim();
+
+        match line.strip_prefix(':') {
+            Some(command) => self.command(command.trim()),
+            None if line.is_empty() => Ok(()),
+            None => self.run(line)
+        }
+    }
+
+    fn run(&mut self, source: &str) -> ErrorResult<()> {
+        let chunk = compile(self.options, source, &mut RunStats::default())?;
+        self.last_chunk = Some(chunk.clone());
+        self.vm.interpret(chunk)?;
+        Ok(())
+    }
+
+    fn command(&mut self, command: &str) -> ErrorResult<()> {
+        let (name, argument) = match command.split_once(' ') {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command, "")
+        };
+
+        match name {
+            "vars" => {
+                let globals = self.vm.globals()
+                    .filter(|(_, value)| !matches!(value, Value::Native(_) | Value::Closure(_)));
+
+                for (name, value) in globals {
+                    println!("{} = {}", name, value);
+                }
+            },
+            "dis" => match &self.last_chunk {
+                Some(chunk) => print!("{}", disassemble_chunk(chunk)),
+                None => return Err(Error::from("Nothing has been compiled yet."))
+            },
+            "load" => {
+                if argument.is_empty() {
+                    return Err(Error::from("Expected a file after ':load'."));
+                }
+
+                let source = read_file(argument)?;
+                self.run(&source)?;
+            },
+            "clear" => {
+                self.vm = new_vm(self.options, StdoutSink::new(self.options.unbuffered))?;
+                self.last_chunk = None;
+            },
+            "help" => print!("{}", REPL_HELP),
+            _ => return Err(format!("Unknown command ':{}'. Type ':help' for the list.", name))
+        }
+
+        Ok(())
+    }
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// Runs the script every time its modification time changes. Errors are
+// reported without leaving watch mode; Ctrl-C exits.
+fn watch(options: &Options, path: &str) -> ErrorResult<()> {
+    let mut last_run: Option<Option<SystemTime>> = None;
+
+    loop {
+        let modified = modified_time(path);
+
+        if last_run != Some(modified) {
+            // Editors often write a file in several steps, so wait for
+            // the modification time to settle before running it.
+            thread::sleep(WATCH_DEBOUNCE);
+
+            if modified_time(path) != modified {
+                continue;
+            }
+
+            if last_run.is_some() {
+                println!("----- {} changed, re-running -----", path);
+            }
+
+            last_run = Some(modified);
+
+            if let Err(error) = run(options) {
+                eprintln!("{}", error);
+            }
+        }
+
+        thread::sleep(WATCH_POLL_INTERVAL);
+    }
+}
+
+// Compiles every file without running it and reports the diagnostics,
+// returning whether all of them compiled.
+fn check_files(options: &Options) -> bool {
+    let mut results = Vec::new();
+
+    for path in &options.paths {
+        let errors = match read_file(path) {
+            Ok(source) => Parser::check(&source).err().unwrap_or_default(),
+            Err(error) => vec![error]
+        };
+
+        results.push((path, errors));
+    }
+
+    if options.json {
+        let files: Vec<_> = results.iter()
+            .map(|(path, errors)| json!({
+                "file": path,
+                "ok": errors.is_empty(),
+                "errors": errors.iter()
+                    .map(|error| diagnostic(Some(path), error))
+                    .collect::<Vec<_>>()
+            }))
+            .collect();
+
+        println!("{}", serde_json::Value::Array(files));
+    } else {
+        for (path, errors) in &results {
+            if errors.is_empty() {
+                println!("{}: ok", path);
+            }
+
+            for error in errors {
+                println!("{}: {}", path, error);
+            }
+        }
+    }
+
+    results.iter().all(|(_, errors)| errors.is_empty())
+}
+
+fn expectations(source: &str) -> Vec<&str> {
+    source.lines()
+        .filter_map(|line| line.split_once("// expect: "))
+        .map(|(_, expected)| expected.trim_end())
+        .collect()
+}
+
+// A test passes when it compiles, runs without a runtime error, every
+// expect_eq and expect_error check in it passes and, if it has
+// `// expect:` comments, it prints exactly the expected lines. Returns
+// how many checks ran.
+fn run_test(session: &mut Session, path: &Path) -> ErrorResult<usize> {
+    let name = path.display().to_string();
+    let source = read_file(&name)?;
+    let chunk = session.compile(&name, &source)?;
+
+    let output = Rc::new(RefCell::new(String::new()));
+    let mut vm = session.new_vm(CaptureSink(output.clone()));
     vm.interpret(chunk)?;
-    Ok(())
-}
\ No newline at end of file
+
+    let results = vm.test_results();
+    let failures: Vec<String> = results.iter()
+        .filter_map(|result| {
+            let failure = result.failure.as_ref()?;
+            Some(format!("\n    line {}: {}: {}", result.line, result.label, failure))
+        })
+        .collect();
+
+    if !failures.is_empty() {
+        return Err(format!("{} of {} checks failed{}", failures.len(), results.len(), failures.concat()));
+    }
+
+    let expected = expectations(&source);
+    if expected.is_empty() {
+        return Ok(results.len())
+    }
+
+    let output = output.borrow();
+    let printed: Vec<_> = output.lines().collect();
+    if printed != expected {
+        return Err(format!("expected {:?} but printed {:?}", expected, printed));
+    }
+
+    Ok(results.len())
+}
+
+// Runs every `.tdx` file in the directory, returning whether all passed.
+fn run_tests(dir: &str) -> ErrorResult<bool> {
+    let entries = fs::read_dir(dir)
+        .map_err(|error| format!("Could not read '{}': {}", dir, error))?;
+
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "tdx"))
+        .collect();
+    paths.sort();
+
+    let mut session = Session::new();
+    let mut failed = 0;
+
+    for path in &paths {
+        match run_test(&mut session, path) {
+            Ok(0) => println!("PASS {}", path.display()),
+            Ok(1) => println!("PASS {} (1 check)", path.display()),
+            Ok(checks) => println!("PASS {} ({} checks)", path.display(), checks),
+            Err(error) => {
+                failed += 1;
+                println!("FAIL {}: {}", path.display(), error);
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", paths.len() - failed, failed);
+    Ok(failed == 0)
+}
+
+// Errors are printed as they are written; returning them from main would
+// print their Debug form, quoted and with escaped line breaks.
+fn main() {
+    if let Err(error) = try_main() {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+fn try_main() -> ErrorResult<()> {
+    let options = parse_args()?;
+
+    if let Some(code) = &options.explain {
+        let entry = codes::find(code).ok_or_else(|| format!("Unknown error code '{}'.", code))?;
+        println!("{}: {}\n\n{}", entry.code, entry.title, entry.explanation);
+        return Ok(())
+    }
+
+    if let Some(dir) = &options.test_dir {
+        if !run_tests(dir)? {
+            process::exit(1);
+        }
+
+        return Ok(())
+    }
+
+    if options.repl {
+        return Repl::new(&options)?.run_lines(io::stdin().lock())
+    }
+
+    if options.format {
+        let source = read_file(&options.paths[0])?;
+        print!("{}", format_source(&source)?);
+        return Ok(())
+    }
+
+    if options.check {
+        if !check_files(&options) {
+            process::exit(1);
+        }
+
+        return Ok(())
+    }
+
+    if options.json && !options.disassemble {
+        if !run_json(&options) {
+            process::exit(1);
+        }
+
+        return Ok(())
+    }
+
+    match options.paths.first() {
+        Some(path) if options.watch => watch(&options, path),
+        _ => run(&options)
+    }
+}
@@ -1,8 +1,14 @@
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
 use num_enum::TryFromPrimitive;
 
+use crate::disassembler::disassemble_instruction;
+use crate::error::ErrorResult;
 use crate::value::Value;
 
-#[derive(TryFromPrimitive, Clone)]
+#[derive(TryFromPrimitive, Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(u8)]
 pub enum OpCode {
     Return,
@@ -23,7 +29,108 @@ pub enum OpCode {
     Pop,
     DefineGlobal,
     GetGlobal,
-    SetGlobal
+    SetGlobal,
+    Invoke,
+    Call,
+    GetLocal,
+    SetLocal,
+    Jump,
+    JumpIfFalse,
+    JumpIfNil,
+    Loop,
+    CheckRange,
+    In,
+    IterNext,
+    PopN,
+    Index,
+    Slice,
+    SetIndex,
+    Dup,
+    NotEqual,
+    GreaterEqual,
+    LessEqual,
+    Zero,
+    One,
+    SmallInt,
+    Defer,
+    RunDefer,
+    EndDefer
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OpInfo {
+    pub name: &'static str,
+    pub operand_bytes: u8,
+    // How many values the instruction reads off the top of the stack,
+    // and the net change in stack depth. Call and Invoke also pop their
+    // arguments and PopN pops its count, on top of what is listed here.
+    pub pops: u8,
+    pub stack_effect: i8
+}
+
+impl OpInfo {
+    const fn new(name: &'static str, operand_bytes: u8, pops: u8, stack_effect: i8) -> Self {
+        Self {
+            name,
+            operand_bytes,
+            pops,
+            stack_effect
+        }
+    }
+}
+
+impl OpCode {
+    // The single place that describes each opcode's encoding. The match
+    // has no catch-all arm, so a new opcode doesn't compile until it has
+    // an entry here.
+    pub const fn info(self) -> OpInfo {
+        match self {
+            OpCode::Return => OpInfo::new("RETURN", 0, 0, 0),
+            OpCode::Constant => OpInfo::new("CONSTANT", 1, 0, 1),
+            OpCode::Nil => OpInfo::new("NIL", 0, 0, 1),
+            OpCode::True => OpInfo::new("TRUE", 0, 0, 1),
+            OpCode::False => OpInfo::new("FALSE", 0, 0, 1),
+            OpCode::Negate => OpInfo::new("NEGATE", 0, 1, 0),
+            OpCode::Add => OpInfo::new("ADD", 0, 2, -1),
+            OpCode::Subtract => OpInfo::new("SUBTRACT", 0, 2, -1),
+            OpCode::Multiply => OpInfo::new("MULTIPLY", 0, 2, -1),
+            OpCode::Divide => OpInfo::new("DIVIDE", 0, 2, -1),
+            OpCode::Not => OpInfo::new("NOT", 0, 1, 0),
+            OpCode::Equal => OpInfo::new("EQUAL", 0, 2, -1),
+            OpCode::Greater => OpInfo::new("GREATER", 0, 2, -1),
+            OpCode::Less => OpInfo::new("LESS", 0, 2, -1),
+            OpCode::Print => OpInfo::new("PRINT", 0, 1, -1),
+            OpCode::Pop => OpInfo::new("POP", 0, 1, -1),
+            OpCode::DefineGlobal => OpInfo::new("DEFINE_GLOBAL", 1, 1, -1),
+            OpCode::GetGlobal => OpInfo::new("GET_GLOBAL", 1, 0, 1),
+            OpCode::SetGlobal => OpInfo::new("SET_GLOBAL", 1, 1, 0),
+            OpCode::Invoke => OpInfo::new("INVOKE", 2, 1, 0),
+            OpCode::Call => OpInfo::new("CALL", 1, 1, 0),
+            OpCode::GetLocal => OpInfo::new("GET_LOCAL", 1, 0, 1),
+            OpCode::SetLocal => OpInfo::new("SET_LOCAL", 1, 1, 0),
+            OpCode::Jump => OpInfo::new("JUMP", 2, 0, 0),
+            OpCode::JumpIfFalse => OpInfo::new("JUMP_IF_FALSE", 2, 1, 0),
+            OpCode::JumpIfNil => OpInfo::new("JUMP_IF_NIL", 2, 1, 0),
+            OpCode::Loop => OpInfo::new("LOOP", 2, 0, 0),
+            OpCode::CheckRange => OpInfo::new("CHECK_RANGE", 0, 2, 0),
+            OpCode::In => OpInfo::new("IN", 0, 2, -1),
+            OpCode::IterNext => OpInfo::new("ITER_NEXT", 1, 0, 2),
+            OpCode::PopN => OpInfo::new("POP_N", 1, 0, 0),
+            OpCode::Index => OpInfo::new("INDEX", 0, 2, -1),
+            OpCode::Slice => OpInfo::new("SLICE", 0, 3, -2),
+            OpCode::SetIndex => OpInfo::new("SET_INDEX", 0, 3, -2),
+            OpCode::Dup => OpInfo::new("DUP", 0, 1, 1),
+            OpCode::NotEqual => OpInfo::new("NOT_EQUAL", 0, 2, -1),
+            OpCode::GreaterEqual => OpInfo::new("GREATER_EQUAL", 0, 2, -1),
+            OpCode::LessEqual => OpInfo::new("LESS_EQUAL", 0, 2, -1),
+            OpCode::Zero => OpInfo::new("ZERO", 0, 0, 1),
+            OpCode::One => OpInfo::new("ONE", 0, 0, 1),
+            OpCode::SmallInt => OpInfo::new("SMALL_INT", 1, 0, 1),
+            OpCode::Defer => OpInfo::new("DEFER", 2, 0, 0),
+            OpCode::RunDefer => OpInfo::new("RUN_DEFER", 0, 0, 0),
+            OpCode::EndDefer => OpInfo::new("END_DEFER", 0, 0, 0)
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -31,6 +138,12 @@ pub struct ValueArray {
     values: Vec<Value>
 }
 
+impl Default for ValueArray {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ValueArray {
     pub fn new() -> Self {
         Self {
@@ -38,17 +151,42 @@ impl ValueArray {
         }
     }
 
-    pub fn write_value(&mut self, value: Value) -> u8 {
+    pub fn write_value(&mut self, value: Value) -> usize {
         self.values.push(value);
-        self.values.len() as u8 - 1        
+        self.values.len() - 1
+    }
+
+    pub fn get_value(&self, idx: u8) -> &Value {
+        &self.values[idx as usize]
+    }
+
+    pub fn try_get_value(&self, idx: u8) -> Option<&Value> {
+        self.values.get(idx as usize)
     }
 
-    pub fn get_value(&self, idx: u8) -> Value {
-        self.values[idx as usize].clone()
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn find_value(&self, value: &Value) -> Option<usize> {
+        self.values.iter().position(|existing| same_constant(existing, value))
     }
 }
 
-#[derive(Clone)]
+// Numbers are compared by their bits so that 0 and -0 stay separate
+// constants and a NaN constant equals itself.
+fn same_constant(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+        _ => a == b
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Byte {
     pub byte: u8,
     pub line: usize
@@ -63,33 +201,687 @@ impl Byte {
     }
 }
 
+// A named local's slot and the bytecode range it is in scope for, from
+// right after its declaration up to the pops that end its block.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LocalInfo {
+    pub slot: u8,
+    pub name: String,
+    // The line the local is declared on.
+    pub line: usize,
+    pub start: usize,
+    pub end: usize
+}
+
 #[derive(Clone)]
 pub struct Chunk {
+    name: String,
     code: Vec<Byte>,
-    value_array: ValueArray
+    value_array: ValueArray,
+    // Globals the code uses before the line of the `var` that declares
+    // them, by name.
+    later_definitions: HashMap<String, usize>,
+    // Debug names of locals, only recorded when the parser is asked to.
+    locals: Vec<LocalInfo>
+}
+
+// Chunks are equal when their code, line info and constants are. The
+// name and local names are left out so a chunk can be compared with a renamed copy.
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.code == other.code
+            && self.constants().len() == other.constants().len()
+            && self.constants().iter().zip(other.constants()).all(|(a, b)| same_constant(a, b))
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
     pub fn new() -> Self {
+        Self::named("script")
+    }
+
+    pub fn named(name: &str) -> Self {
         Self {
+            name: name.to_string(),
             code: Vec::new(),
-            value_array: ValueArray::new()
+            value_array: ValueArray::new(),
+            later_definitions: HashMap::new(),
+            locals: Vec::new()
         }
     }
 
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn write_byte(&mut self, byte: Byte) {
         self.code.push(byte);
     }
 
-    pub fn write_value(&mut self, value: Value) -> u8 {
+    pub fn write_value(&mut self, value: Value) -> usize {
         self.value_array.write_value(value)
     }
 
+    pub fn find_value(&self, value: &Value) -> Option<usize> {
+        self.value_array.find_value(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    pub fn constants_len(&self) -> usize {
+        self.value_array.len()
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.value_array.values
+    }
+
+    pub fn constants_mut(&mut self) -> &mut [Value] {
+        &mut self.value_array.values
+    }
+
+    // Swaps one constant for another, e.g. a placeholder for a build id.
+    // Constants that name a global or a method must stay strings.
+    pub fn replace_constant(&mut self, index: usize, value: Value) -> ErrorResult<()> {
+        if index >= self.constants_len() {
+            return Err(format!("Constant {} doesn't exist; the chunk has {}.", index, self.constants_len()));
+        }
+
+        if !matches!(value, Value::String(_)) && self.is_name_constant(index) {
+            return Err(format!(
+                "Constant {} is the name of a global or method, so it can't be replaced with a {}.",
+                index,
+                value.type_name()
+            ));
+        }
+
+        self.value_array.values[index] = value;
+        Ok(())
+    }
+
+    fn is_name_constant(&self, index: usize) -> bool {
+        self.instructions().any(|decoded| match decoded.instruction {
+            Instruction::DefineGlobal { index: name, .. }
+                | Instruction::GetGlobal { index: name, .. }
+                | Instruction::SetGlobal { index: name, .. }
+                | Instruction::Invoke { index: name, .. } => name as usize == index,
+            _ => false
+        })
+    }
+
+    // Local names recorded for the removed code go with it.
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.locals.retain(|local| local.start < len);
+    }
+
+    // Overwrites a byte that has already been written, keeping its line.
+    pub fn patch_byte(&mut self, idx: usize, byte: u8) {
+        self.code[idx].byte = byte;
+    }
+
     pub fn get_byte(&self, idx: usize) -> Byte {
         self.code[idx].clone()
     }
 
-    pub fn get_value(&self, idx: u8) -> Value {
+    pub fn try_get_byte(&self, idx: usize) -> Option<&Byte> {
+        self.code.get(idx)
+    }
+
+    pub fn get_value(&self, idx: u8) -> &Value {
         self.value_array.get_value(idx)
     }
-}
\ No newline at end of file
+
+    pub fn try_get_value(&self, idx: u8) -> Option<&Value> {
+        self.value_array.try_get_value(idx)
+    }
+
+    // Walks every path through the bytecode and checks the stack depth:
+    // no instruction may pop or read a local below the bottom, paths
+    // that meet must agree on the depth, and the stack must be empty at
+    // Return. Compiled chunks always pass, so a failure means a
+    // compiler bug or a hand-made chunk.
+    pub fn verify_stack(&self) -> ErrorResult<()> {
+        self.stack_depths().map(|_| ())
+    }
+
+    // The stack depth before each instruction, by offset, or None for
+    // offsets no path reaches. Fails like verify_stack.
+    pub(crate) fn stack_depths(&self) -> ErrorResult<Vec<Option<usize>>> {
+        let mut depths: Vec<Option<usize>> = vec![None; self.code.len()];
+        let mut pending = vec![(0, 0)];
+
+        while let Some((offset, depth)) = pending.pop() {
+            match depths.get(offset) {
+                None => return Err(format!("Code at {:04} jumps past the end of the chunk.", offset)),
+                Some(Some(known)) if *known != depth => {
+                    return Err(format!("Stack depth at {:04} is both {} and {}.", offset, known, depth))
+                },
+                Some(Some(_)) => continue,
+                Some(None) => depths[offset] = Some(depth)
+            }
+
+            let decoded = match (Instructions { chunk: self, offset }).next() {
+                Some(decoded) => decoded,
+                None => return Err(format!("Code at {:04} runs past the end of the chunk.", offset))
+            };
+
+            let info = match decoded.instruction.opcode() {
+                Some(opcode) => opcode.info(),
+                None => return Err(format!("Invalid instruction at {:04}.", offset))
+            };
+
+            let extra = match decoded.instruction {
+                Instruction::Call { arg_count } | Instruction::Invoke { arg_count, .. } => arg_count as usize,
+                Instruction::PopN { count } => count as usize,
+                _ => 0
+            };
+
+            if depth < info.pops as usize + extra {
+                return Err(format!("Stack underflow at {:04} {}.", offset, info.name));
+            }
+
+            let slot = match decoded.instruction {
+                Instruction::GetLocal { slot } | Instruction::SetLocal { slot } => Some(slot as usize),
+                Instruction::IterNext { slot } => Some(slot as usize + 1),
+                _ => None
+            };
+
+            if slot.is_some_and(|slot| slot >= depth) {
+                return Err(format!("Local slot out of range at {:04} {}.", offset, info.name));
+            }
+
+            let after = (depth as isize + info.stack_effect as isize - extra as isize) as usize;
+            let next = offset + decoded.instruction.size();
+
+            match decoded.instruction {
+                Instruction::Return if depth != 0 => {
+                    return Err(format!("Stack depth at RETURN {:04} is {} instead of 0.", offset, depth))
+                },
+                Instruction::Return => (),
+                // A deferred body is entered from RUN_DEFER at the depth
+                // it was written at, and returns there when it ends.
+                Instruction::EndDefer => (),
+                Instruction::Jump { offset: jump } => pending.push((next + jump as usize, after)),
+                Instruction::Loop { offset: jump } => match next.checked_sub(jump as usize) {
+                    Some(target) => pending.push((target, after)),
+                    None => return Err(format!("Loop at {:04} jumps before the start of the chunk.", offset))
+                },
+                Instruction::JumpIfFalse { offset: jump }
+                | Instruction::JumpIfNil { offset: jump }
+                | Instruction::Defer { offset: jump } => {
+                    pending.push((next, after));
+                    pending.push((next + jump as usize, after));
+                },
+                _ => pending.push((next, after))
+            }
+        }
+
+        Ok(depths)
+    }
+
+    pub fn instructions(&self) -> Instructions<'_> {
+        self.instructions_from(0)
+    }
+
+    // Decodes from `offset` on, which must be where an instruction starts.
+    pub fn instructions_from(&self, offset: usize) -> Instructions<'_> {
+        Instructions {
+            chunk: self,
+            offset
+        }
+    }
+
+    pub fn add_later_definition(&mut self, name: &str, line: usize) {
+        self.later_definitions.entry(name.to_string()).or_insert(line);
+    }
+
+    // The line of the declaration of a global that is used before it,
+    // for hinting at the order when the global turns out undefined.
+    pub fn later_definition(&self, name: &str) -> Option<usize> {
+        self.later_definitions.get(name).copied()
+    }
+
+    // Returns the entry's index, for setting its end once the scope closes.
+    pub fn add_local_info(&mut self, local: LocalInfo) -> usize {
+        self.locals.push(local);
+        self.locals.len() - 1
+    }
+
+    pub fn end_local_info(&mut self, index: usize, end: usize) {
+        if let Some(local) = self.locals.get_mut(index) {
+            local.end = end;
+        }
+    }
+
+    pub fn local_infos(&self) -> &[LocalInfo] {
+        &self.locals
+    }
+
+    pub(crate) fn local_infos_mut(&mut self) -> &mut Vec<LocalInfo> {
+        &mut self.locals
+    }
+
+    // The name of the local in `slot` at the instruction at `offset`, if
+    // local names were recorded.
+    pub fn local_name(&self, slot: u8, offset: usize) -> Option<&str> {
+        self.locals.iter()
+            .rev()
+            .find(|local| local.slot == slot && local.start <= offset && offset < local.end)
+            .map(|local| local.name.as_str())
+    }
+
+    // Drops the local names, e.g. before shipping a chunk.
+    pub fn strip_debug_info(&mut self) {
+        self.locals.clear();
+    }
+
+    // The source line of the byte at `offset`, operand bytes included.
+    // Lines are stored per byte, so this is a plain lookup.
+    pub fn line_of(&self, offset: usize) -> Option<usize> {
+        self.code.get(offset).map(|byte| byte.line)
+    }
+
+    // The offsets of the instructions compiled from `line`, in order.
+    pub fn offsets_for_line(&self, line: usize) -> impl Iterator<Item = usize> + '_ {
+        self.instructions()
+            .filter(move |decoded| decoded.line == line)
+            .map(|decoded| decoded.offset)
+    }
+
+    // Every line that has code, in ascending order.
+    pub fn lines(&self) -> impl Iterator<Item = usize> {
+        self.code.iter()
+            .map(|byte| byte.line)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "op"))]
+pub enum Instruction {
+    Return,
+    Constant { index: u8, value: Value },
+    Nil,
+    True,
+    False,
+    Negate,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Not,
+    Equal,
+    Greater,
+    Less,
+    Print,
+    Pop,
+    DefineGlobal { index: u8, name: Value },
+    GetGlobal { index: u8, name: Value },
+    SetGlobal { index: u8, name: Value },
+    Invoke { index: u8, name: Value, arg_count: u8 },
+    Call { arg_count: u8 },
+    GetLocal { slot: u8 },
+    SetLocal { slot: u8 },
+    Jump { offset: u16 },
+    JumpIfFalse { offset: u16 },
+    JumpIfNil { offset: u16 },
+    Loop { offset: u16 },
+    CheckRange,
+    In,
+    IterNext { slot: u8 },
+    PopN { count: u8 },
+    Index,
+    Slice,
+    SetIndex,
+    Dup,
+    NotEqual,
+    GreaterEqual,
+    LessEqual,
+    Zero,
+    One,
+    SmallInt { value: u8 },
+    // Registers the deferred body that follows and jumps over it.
+    Defer { offset: u16 },
+    RunDefer,
+    EndDefer,
+
+    // A byte that isn't a valid opcode, or an opcode whose operands
+    // run past the end of the chunk.
+    Invalid { byte: u8 }
+}
+
+impl Instruction {
+    pub fn opcode(&self) -> Option<OpCode> {
+        let opcode = match self {
+            Instruction::Return => OpCode::Return,
+            Instruction::Constant { .. } => OpCode::Constant,
+            Instruction::Nil => OpCode::Nil,
+            Instruction::True => OpCode::True,
+            Instruction::False => OpCode::False,
+            Instruction::Negate => OpCode::Negate,
+            Instruction::Add => OpCode::Add,
+            Instruction::Subtract => OpCode::Subtract,
+            Instruction::Multiply => OpCode::Multiply,
+            Instruction::Divide => OpCode::Divide,
+            Instruction::Not => OpCode::Not,
+            Instruction::Equal => OpCode::Equal,
+            Instruction::Greater => OpCode::Greater,
+            Instruction::Less => OpCode::Less,
+            Instruction::Print => OpCode::Print,
+            Instruction::Pop => OpCode::Pop,
+            Instruction::DefineGlobal { .. } => OpCode::DefineGlobal,
+            Instruction::GetGlobal { .. } => OpCode::GetGlobal,
+            Instruction::SetGlobal { .. } => OpCode::SetGlobal,
+            Instruction::Invoke { .. } => OpCode::Invoke,
+            Instruction::Call { .. } => OpCode::Call,
+            Instruction::GetLocal { .. } => OpCode::GetLocal,
+            Instruction::SetLocal { .. } => OpCode::SetLocal,
+            Instruction::Jump { .. } => OpCode::Jump,
+            Instruction::JumpIfFalse { .. } => OpCode::JumpIfFalse,
+            Instruction::JumpIfNil { .. } => OpCode::JumpIfNil,
+            Instruction::Loop { .. } => OpCode::Loop,
+            Instruction::CheckRange => OpCode::CheckRange,
+            Instruction::In => OpCode::In,
+            Instruction::IterNext { .. } => OpCode::IterNext,
+            Instruction::PopN { .. } => OpCode::PopN,
+            Instruction::Index => OpCode::Index,
+            Instruction::Slice => OpCode::Slice,
+            Instruction::SetIndex => OpCode::SetIndex,
+            Instruction::Dup => OpCode::Dup,
+            Instruction::NotEqual => OpCode::NotEqual,
+            Instruction::GreaterEqual => OpCode::GreaterEqual,
+            Instruction::LessEqual => OpCode::LessEqual,
+            Instruction::Zero => OpCode::Zero,
+            Instruction::One => OpCode::One,
+            Instruction::SmallInt { .. } => OpCode::SmallInt,
+            Instruction::Defer { .. } => OpCode::Defer,
+            Instruction::RunDefer => OpCode::RunDefer,
+            Instruction::EndDefer => OpCode::EndDefer,
+            Instruction::Invalid { .. } => return None
+        };
+
+        Some(opcode)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.opcode().map_or("INVALID", |opcode| opcode.info().name)
+    }
+
+    // The number of bytes the instruction occupies, opcode included.
+    pub fn size(&self) -> usize {
+        self.opcode().map_or(1, |opcode| 1 + opcode.info().operand_bytes as usize)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DecodedInstruction {
+    pub offset: usize,
+    pub line: usize,
+    pub instruction: Instruction
+}
+
+pub struct Instructions<'a> {
+    chunk: &'a Chunk,
+    offset: usize
+}
+
+impl<'a> Instructions<'a> {
+    fn operand(&self) -> Option<u8> {
+        self.operand_at(1)
+    }
+
+    fn short_operand(&self) -> Option<u16> {
+        Some(u16::from_be_bytes([self.operand_at(1)?, self.operand_at(2)?]))
+    }
+
+    fn operand_at(&self, distance: usize) -> Option<u8> {
+        self.chunk.code.get(self.offset + distance).map(|byte| byte.byte)
+    }
+
+    fn constant_operand<F>(&self, make: F) -> Option<Instruction>
+    where
+        F: FnOnce(u8, Value) -> Instruction
+    {
+        let index = self.operand()?;
+        let value = self.chunk.value_array.values.get(index as usize)?.clone();
+        Some(make(index, value))
+    }
+
+    fn decode(&self, byte: u8) -> Option<Instruction> {
+        let instruction = match OpCode::try_from(byte).ok()? {
+            OpCode::Return => Instruction::Return,
+            OpCode::Constant => self.constant_operand(|index, value| Instruction::Constant { index, value })?,
+            OpCode::Nil => Instruction::Nil,
+            OpCode::True => Instruction::True,
+            OpCode::False => Instruction::False,
+            OpCode::Negate => Instruction::Negate,
+            OpCode::Add => Instruction::Add,
+            OpCode::Subtract => Instruction::Subtract,
+            OpCode::Multiply => Instruction::Multiply,
+            OpCode::Divide => Instruction::Divide,
+            OpCode::Not => Instruction::Not,
+            OpCode::Equal => Instruction::Equal,
+            OpCode::Greater => Instruction::Greater,
+            OpCode::Less => Instruction::Less,
+            OpCode::Print => Instruction::Print,
+            OpCode::Pop => Instruction::Pop,
+            OpCode::DefineGlobal => self.constant_operand(|index, name| Instruction::DefineGlobal { index, name })?,
+            OpCode::GetGlobal => self.constant_operand(|index, name| Instruction::GetGlobal { index, name })?,
+            OpCode::SetGlobal => self.constant_operand(|index, name| Instruction::SetGlobal { index, name })?,
+            OpCode::Invoke => {
+                let arg_count = self.operand_at(2)?;
+                self.constant_operand(|index, name| Instruction::Invoke { index, name, arg_count })?
+            },
+            OpCode::Call => Instruction::Call { arg_count: self.operand()? },
+            OpCode::GetLocal => Instruction::GetLocal { slot: self.operand()? },
+            OpCode::SetLocal => Instruction::SetLocal { slot: self.operand()? },
+            OpCode::Jump => Instruction::Jump { offset: self.short_operand()? },
+            OpCode::JumpIfFalse => Instruction::JumpIfFalse { offset: self.short_operand()? },
+            OpCode::JumpIfNil => Instruction::JumpIfNil { offset: self.short_operand()? },
+            OpCode::Loop => Instruction::Loop { offset: self.short_operand()? },
+            OpCode::CheckRange => Instruction::CheckRange,
+            OpCode::In => Instruction::In,
+            OpCode::IterNext => Instruction::IterNext { slot: self.operand()? },
+            OpCode::PopN => Instruction::PopN { count: self.operand()? },
+            OpCode::Index => Instruction::Index,
+            OpCode::Slice => Instruction::Slice,
+            OpCode::SetIndex => Instruction::SetIndex,
+            OpCode::Dup => Instruction::Dup,
+            OpCode::NotEqual => Instruction::NotEqual,
+            OpCode::GreaterEqual => Instruction::GreaterEqual,
+            OpCode::LessEqual => Instruction::LessEqual,
+            OpCode::Zero => Instruction::Zero,
+            OpCode::One => Instruction::One,
+            OpCode::SmallInt => Instruction::SmallInt { value: self.operand()? },
+            OpCode::Defer => Instruction::Defer { offset: self.short_operand()? },
+            OpCode::RunDefer => Instruction::RunDefer,
+            OpCode::EndDefer => Instruction::EndDefer
+        };
+
+        Some(instruction)
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = DecodedInstruction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let byte = self.chunk.code.get(self.offset)?;
+        let instruction = self.decode(byte.byte)
+            .unwrap_or(Instruction::Invalid { byte: byte.byte });
+
+        let decoded = DecodedInstruction {
+            offset: self.offset,
+            line: byte.line,
+            instruction
+        };
+
+        self.offset += decoded.instruction.size();
+        Some(decoded)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum InstructionChange {
+    // Offsets are in the chunk the instruction comes from.
+    Removed(DecodedInstruction),
+    Added(DecodedInstruction),
+    Changed { old: DecodedInstruction, new: DecodedInstruction }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ConstantChange {
+    Removed { index: usize, value: Value },
+    Added { index: usize, value: Value },
+    Changed { index: usize, old: Value, new: Value }
+}
+
+// The differences between two chunks, as produced by `diff`.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct ChunkDiff {
+    pub instructions: Vec<InstructionChange>,
+    pub constants: Vec<ConstantChange>
+}
+
+impl ChunkDiff {
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty() && self.constants.is_empty()
+    }
+}
+
+// Instructions are matched up by their longest common subsequence,
+// comparing the instruction and its line but not its offset, so code
+// inserted early doesn't show everything after it as changed. A removal
+// directly followed by an addition is reported as a change. Constants
+// are compared index by index since instructions refer to them that way.
+pub fn diff(a: &Chunk, b: &Chunk) -> ChunkDiff {
+    let old: Vec<_> = a.instructions().collect();
+    let new: Vec<_> = b.instructions().collect();
+    let same = |i: usize, j: usize| old[i].line == new[j].line && old[i].instruction == new[j].instruction;
+
+    // common[i][j] is the length of the longest common subsequence of
+    // old[i..] and new[j..].
+    let mut common = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if same(i, j) {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut result = ChunkDiff::default();
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && same(i, j) {
+            flush_changes(&mut result.instructions, &mut removed, &mut added);
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            removed.push(old[i].clone());
+            i += 1;
+        } else {
+            added.push(new[j].clone());
+            j += 1;
+        }
+    }
+    flush_changes(&mut result.instructions, &mut removed, &mut added);
+
+    let (old_constants, new_constants) = (a.constants(), b.constants());
+    for index in 0..old_constants.len().max(new_constants.len()) {
+        let change = match (old_constants.get(index), new_constants.get(index)) {
+            (Some(old), Some(new)) if same_constant(old, new) => continue,
+            (Some(old), Some(new)) => ConstantChange::Changed { index, old: old.clone(), new: new.clone() },
+            (Some(old), None) => ConstantChange::Removed { index, value: old.clone() },
+            (None, Some(new)) => ConstantChange::Added { index, value: new.clone() },
+            (None, None) => unreachable!()
+        };
+
+        result.constants.push(change);
+    }
+
+    result
+}
+
+fn flush_changes(
+    changes: &mut Vec<InstructionChange>,
+    removed: &mut Vec<DecodedInstruction>,
+    added: &mut Vec<DecodedInstruction>
+) {
+    let paired = removed.len().min(added.len());
+    let mut removed = removed.drain(..);
+    let mut added = added.drain(..);
+
+    for _ in 0..paired {
+        changes.push(InstructionChange::Changed {
+            old: removed.next().unwrap(),
+            new: added.next().unwrap()
+        });
+    }
+
+    changes.extend(removed.map(InstructionChange::Removed));
+    changes.extend(added.map(InstructionChange::Added));
+}
+
+// Renders like a unified diff without context lines: `-` for the old
+// chunk, `+` for the new one, instructions in disassembler format.
+impl Display for ChunkDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        if !self.instructions.is_empty() {
+            writeln!(f, "@@ instructions @@")?;
+        }
+
+        for change in &self.instructions {
+            match change {
+                InstructionChange::Removed(old) => writeln!(f, "-{}", disassemble_instruction(old, false))?,
+                InstructionChange::Added(new) => writeln!(f, "+{}", disassemble_instruction(new, false))?,
+                InstructionChange::Changed { old, new } => {
+                    writeln!(f, "-{}", disassemble_instruction(old, false))?;
+                    writeln!(f, "+{}", disassemble_instruction(new, false))?;
+                }
+            }
+        }
+
+        if !self.constants.is_empty() {
+            writeln!(f, "@@ constants @@")?;
+        }
+
+        for change in &self.constants {
+            match change {
+                ConstantChange::Removed { index, value } => writeln!(f, "-{:4} {}", index, value.repr())?,
+                ConstantChange::Added { index, value } => writeln!(f, "+{:4} {}", index, value.repr())?,
+                ConstantChange::Changed { index, old, new } => {
+                    writeln!(f, "-{:4} {}", index, old.repr())?;
+                    writeln!(f, "+{:4} {}", index, new.repr())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,457 @@
+use crate::error::{Error, NativeError};
+
+// Stable codes for compile and runtime errors, shown in messages as
+// `[line 3] Error E014: ...` and explained in full by the CLI's
+// `--explain`. Each error picks its code where it is raised. Errors
+// raised by the host's own callbacks have no code.
+pub struct ErrorCode {
+    pub code: &'static str,
+    pub title: &'static str,
+    // The longer description printed by `--explain`, with an example and
+    // the usual fixes.
+    pub explanation: &'static str
+}
+
+impl ErrorCode {
+    // An error with this code, for natives and methods to return.
+    pub fn error(&self, message: impl Into<Error>) -> NativeError {
+        NativeError {
+            code: Some(self.code),
+            message: message.into()
+        }
+    }
+}
+
+pub const UNEXPECTED_CHARACTER: &ErrorCode = &ErrorCode {
+    code: "E001",
+    title: "Unexpected character",
+    explanation: "\
+The source contains a character that doesn't start any token, such as
+`@`, `#` or a lone `?`.
+
+Example:
+    var total = 3 @ 4;
+
+Fix: remove the character or replace it with the operator you meant.
+`?` is only valid as part of `?.`."
+};
+
+pub const MALFORMED_NUMBER: &ErrorCode = &ErrorCode {
+    code: "E002",
+    title: "Malformed number literal",
+    explanation: "\
+A number literal isn't written the way the language expects, or is too
+large to represent.
+
+Example:
+    var half = .5;
+    var version = 1.2.3;
+    var price = 1,5;
+
+Fix: write at least one digit on both sides of the decimal point, as in
+`0.5`, always use '.' for decimals, and use a string for values like
+version numbers."
+};
+
+pub const UNTERMINATED_STRING: &ErrorCode = &ErrorCode {
+    code: "E003",
+    title: "Unterminated string",
+    explanation: "\
+A string literal has no closing `\"` before the end of the file.
+
+Example:
+    print \"hello;
+
+Fix: add the closing quote. Strings may span lines, so the error is
+reported where the file ends rather than where the string starts."
+};
+
+pub const MISSING_SEMICOLON: &ErrorCode = &ErrorCode {
+    code: "E004",
+    title: "Missing semicolon",
+    explanation: "\
+A statement isn't followed by `;`. The error usually points at the start
+of the next line, since that's where the parser notices.
+
+Example:
+    var a = 1
+    print a;
+
+Fix: end the statement with `;`."
+};
+
+pub const EXPECTED_EXPRESSION: &ErrorCode = &ErrorCode {
+    code: "E005",
+    title: "Expected an expression",
+    explanation: "\
+An expression was needed, but the next token can't start one.
+
+Example:
+    var a = ;
+    print * 2;
+
+Fix: supply the missing value, or remove the stray operator."
+};
+
+pub const UNEXPECTED_TOKEN: &ErrorCode = &ErrorCode {
+    code: "E006",
+    title: "Unexpected token",
+    explanation: "\
+The parser needed a particular token, such as a closing bracket or a
+name, and found something else. The message says what was expected and
+what was found.
+
+Example:
+    print upper(\"a\";
+    for 1 in 0..3 {}
+
+Fix: add the missing token. Unbalanced brackets are the usual cause."
+};
+
+pub const VALUE_COUNT: &ErrorCode = &ErrorCode {
+    code: "E007",
+    title: "Wrong number of values",
+    explanation: "\
+A declaration or assignment with several names has a different number
+of values on the right.
+
+Example:
+    var a, b = 1, 2, 3;
+
+Fix: give exactly one value per name."
+};
+
+pub const DUPLICATE_VARIABLE: &ErrorCode = &ErrorCode {
+    code: "E008",
+    title: "Duplicate variable",
+    explanation: "\
+The same name is declared twice in one block, or listed twice in one
+declaration or assignment.
+
+Example:
+    {
+        var a = 1;
+        var a = 2;
+    }
+
+Fix: rename one of them, or assign to the existing variable instead of
+declaring it again."
+};
+
+pub const DEFINE_CHANGED: &ErrorCode = &ErrorCode {
+    code: "E010",
+    title: "Define can't be changed",
+    explanation: "\
+The name is a define supplied by the host, which compiles to a fixed
+value. It can't be declared or assigned.
+
+Example:
+    var DEBUG = false;
+
+Fix: pick another name, or change the define in the host."
+};
+
+pub const COMPILE_LIMIT: &ErrorCode = &ErrorCode {
+    code: "E011",
+    title: "Compile limit exceeded",
+    explanation: "\
+The script exceeds one of the compiler's size limits: locals in scope,
+nesting depth, constants per chunk, arguments per call or the length of
+a jump. The message names the limit.
+
+Fix: split the code into smaller pieces, or raise the limit through the
+host's Limits if it controls them."
+};
+
+pub const CHAINED_COMPARISON: &ErrorCode = &ErrorCode {
+    code: "E012",
+    title: "Chained comparison",
+    explanation: "\
+Comparisons can't be chained: `0 < x < 10` would compare the bool from
+`0 < x` with 10.
+
+Example:
+    print 0 < x < 10;
+
+Fix: compare each pair separately. Add parentheses if comparing a
+comparison's result really is intended."
+};
+
+pub const MISPLACED_DEFER: &ErrorCode = &ErrorCode {
+    code: "E013",
+    title: "Misplaced defer",
+    explanation: "\
+`defer` runs its statement when the enclosing block ends, so it is only
+allowed inside a block, and a deferred statement can't be another
+`defer`.
+
+Example:
+    defer print \"done\";
+
+Fix: move the `defer` into the block it should clean up after."
+};
+
+pub const UNDEFINED_VARIABLE: &ErrorCode = &ErrorCode {
+    code: "E014",
+    title: "Undefined variable",
+    explanation: "\
+A global was read or assigned before any `var` defined it. The message
+suggests a similar name when there is one, and points at the
+declaration if it comes later in the script.
+
+Example:
+    var count = 1;
+    print cuont;
+
+Fix: correct the spelling, or move the declaration above the first
+use."
+};
+
+pub const UNSUPPORTED_SYNTAX: &ErrorCode = &ErrorCode {
+    code: "E015",
+    title: "Unsupported syntax",
+    explanation: "\
+The syntax belongs to a feature the language doesn't have yet, such as
+classes or property access.
+
+Example:
+    print this;
+    print name.length;
+
+Fix: use a method call such as `name.len()` or a native instead."
+};
+
+pub const MATCH_WITHOUT_DEFAULT: &ErrorCode = &ErrorCode {
+    code: "E016",
+    title: "Match without a '_' arm",
+    explanation: "\
+Every match must end with a `_` arm, so it has a value even when no
+pattern matches.
+
+Example:
+    print match x { 1 => \"one\", 2 => \"two\" };
+
+Fix: add a final `_ => ...` arm."
+};
+
+pub const OPERAND_TYPE: &ErrorCode = &ErrorCode {
+    code: "E017",
+    title: "Wrong operand type",
+    explanation: "\
+An operator was applied to values it doesn't support, such as
+subtracting strings or negating a bool. Only `+` works on strings, and
+only on two strings.
+
+Example:
+    print \"a\" - 1;
+    print 1 + \"1\";
+
+Fix: convert the value first, for example with num()."
+};
+
+pub const INVALID_ARGUMENT: &ErrorCode = &ErrorCode {
+    code: "E018",
+    title: "Invalid argument",
+    explanation: "\
+A native was called with an argument of the wrong type or with a value
+it can't use.
+
+Example:
+    print upper(42);
+    print repeat(\"ab\", -1);
+
+Fix: check the argument's type or range before calling."
+};
+
+pub const ARGUMENT_COUNT: &ErrorCode = &ErrorCode {
+    code: "E019",
+    title: "Wrong number of arguments",
+    explanation: "\
+A native or method was called with more or fewer arguments than it
+takes.
+
+Example:
+    print len(\"a\", \"b\");
+
+Fix: pass the number of arguments the message asks for."
+};
+
+pub const NOT_CALLABLE: &ErrorCode = &ErrorCode {
+    code: "E020",
+    title: "Not callable",
+    explanation: "\
+A value that isn't a function was called, or a method was called on a
+type that doesn't have it.
+
+Example:
+    var a = 1;
+    a();
+    print a.upper();
+
+Fix: call a native or method that exists for the value's type."
+};
+
+pub const INVALID_INDEX: &ErrorCode = &ErrorCode {
+    code: "E021",
+    title: "Invalid index or slice",
+    explanation: "\
+Only strings can be indexed, with whole numbers within their length.
+Strings can't be changed in place.
+
+Example:
+    var s = \"abc\";
+    print s[5];
+    s[0] = \"x\";
+
+Fix: check the index against len(s), and build a new string instead of
+assigning to an index."
+};
+
+pub const INVALID_RANGE: &ErrorCode = &ErrorCode {
+    code: "E022",
+    title: "Invalid loop range",
+    explanation: "\
+A `for` loop's range needs whole number bounds with the start no greater
+than the end, and only ranges and strings can be iterated. The bounds
+must lie within 2^53 (9007199254740992) of zero, where every whole
+number can still be counted exactly.
+
+Example:
+    for i in 5..1 {}
+    for c in 42 {}
+
+Fix: swap the bounds or iterate over a string."
+};
+
+pub const RUNTIME_LIMIT: &ErrorCode = &ErrorCode {
+    code: "E023",
+    title: "Runtime limit exceeded",
+    explanation: "\
+The script ran past a limit set by the host: instructions, time, stack
+size or string length. Sandboxed scripts have the tightest limits.
+
+Example:
+    var s = \"x\";
+    for i in 0..100 { s = s + s; }
+
+Fix: do less work per run, or ask the host to raise the limit."
+};
+
+pub const ACCESS_DENIED: &ErrorCode = &ErrorCode {
+    code: "E024",
+    title: "File, environment or command access denied",
+    explanation: "\
+A file, environment or command native was refused or failed. Scripts
+only get file access inside the root the host grants, only to the
+environment variables it allows, and only run commands when exec is
+allowed. Without a grant the natives aren't defined at all, which is
+reported as E014 instead. A command the host's exec policy turns down
+fails with the host's reason and no code.
+
+Example:
+    print read_file(\"../secret.txt\");
+
+Fix: use paths inside the file root, or ask the host to grant access."
+};
+
+pub const INVALID_FORMAT: &ErrorCode = &ErrorCode {
+    code: "E025",
+    title: "Invalid format spec",
+    explanation: "\
+A format() placeholder has a spec that isn't supported or doesn't fit
+its value.
+
+Example:
+    print format(255, \"x\");
+    print format(3, \".2d\");
+
+Fix: use `s` for strings and `d` or `f` for numbers, with a precision
+only on `f`."
+};
+
+pub const OUTPUT_FAILED: &ErrorCode = &ErrorCode {
+    code: "E026",
+    title: "Output failed",
+    explanation: "\
+The host's output sink failed to write what the script printed, for
+example because the pipe it writes to was closed.
+
+Example:
+    tundraix-cli script.tdx | head -1
+
+Fix: keep the output open, or have the host set
+PrintErrorPolicy::Ignore to drop output instead of stopping."
+};
+
+pub const MALFORMED_BYTECODE: &ErrorCode = &ErrorCode {
+    code: "E027",
+    title: "Malformed bytecode",
+    explanation: "\
+The VM ran a chunk that the compiler didn't produce, or one that was
+damaged after compiling.
+
+Fix: compile the source again instead of running stored or edited
+bytecode."
+};
+
+pub const INVALID_KEY: &ErrorCode = &ErrorCode {
+    code: "E028",
+    title: "Invalid key",
+    explanation: "\
+A value that has no stable hash, such as NaN or a function, was used as
+a key.
+
+Fix: use strings, numbers other than NaN, bools or nil as keys."
+};
+
+pub const COMMAND_FAILED: &ErrorCode = &ErrorCode {
+    code: "E029",
+    title: "Command failed",
+    explanation: "\
+A command run with exec() couldn't be started or printed more than a
+string can hold. A command that runs but fails isn't an error; read its
+exit status with exec_status() and its standard error with
+exec_stderr().
+
+Example:
+    exec(\"no-such-command\");
+
+Fix: check the command and its path, or catch the failure with
+expect_error() when it is expected."
+};
+
+pub const ERROR_CODES: &[&ErrorCode] = &[
+    UNEXPECTED_CHARACTER,
+    MALFORMED_NUMBER,
+    UNTERMINATED_STRING,
+    MISSING_SEMICOLON,
+    EXPECTED_EXPRESSION,
+    UNEXPECTED_TOKEN,
+    VALUE_COUNT,
+    DUPLICATE_VARIABLE,
+    DEFINE_CHANGED,
+    COMPILE_LIMIT,
+    CHAINED_COMPARISON,
+    MISPLACED_DEFER,
+    UNDEFINED_VARIABLE,
+    UNSUPPORTED_SYNTAX,
+    MATCH_WITHOUT_DEFAULT,
+    OPERAND_TYPE,
+    INVALID_ARGUMENT,
+    ARGUMENT_COUNT,
+    NOT_CALLABLE,
+    INVALID_INDEX,
+    INVALID_RANGE,
+    RUNTIME_LIMIT,
+    ACCESS_DENIED,
+    INVALID_FORMAT,
+    OUTPUT_FAILED,
+    MALFORMED_BYTECODE,
+    INVALID_KEY,
+    COMMAND_FAILED
+];
+
+// Looks a code up by name, ignoring case.
+pub fn find(code: &str) -> Option<&'static ErrorCode> {
+    ERROR_CODES.iter().copied().find(|entry| entry.code.eq_ignore_ascii_case(code))
+}
@@ -1,15 +1,79 @@
-use std::fmt::Write;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::rc::Rc;
 
 use crate::tokenizer::{Tokenizer, TokenType, Token};
-use crate::chunk::{Chunk, Byte, OpCode};
+use crate::chunk::{Chunk, Byte, Instruction, LocalInfo, OpCode};
+use crate::codes::{self, ErrorCode};
 use crate::error::{Error, ErrorResult};
+use crate::limits::Limits;
+use crate::natives::{FEATURES, STANDARD_NATIVES};
 use crate::value::Value;
 
-pub struct Parser {
-    tokenizer: Tokenizer,
+// Evaluation order is part of the language: operands, call arguments,
+// method receivers before their arguments, and the values of `var` and
+// parallel assignment lists are all evaluated left to right, whatever
+// the precedence, so side effects in them happen in source order.
+// Constant folding only merges operands that are constants, so it can't
+// change that order, and later optimizations must keep it too.
+pub struct Parser<'src> {
+    tokenizer: Tokenizer<'src>,
     chunk: Chunk,
-    current: Token,
-    previous: Token
+    current: Token<'src>,
+    previous: Token<'src>,
+    errors: Vec<Error>,
+    locals: Vec<Local<'src>>,
+    defers: Vec<Deferred>,
+    scope_depth: usize,
+    nesting: usize,
+    // The chunk length right after the last comparison was emitted, to
+    // spot a comparison whose left operand is another comparison.
+    comparison_end: Option<usize>,
+    limits: Limits,
+    defines: HashMap<String, Value>,
+    // Where the last Constant instruction starts, to spot operands that
+    // are a single constant.
+    last_constant: Option<usize>,
+    // Set when advance reports an error token, so the errors it causes in
+    // the rest of the declaration aren't reported too.
+    skipped_error: bool,
+    info: CompileInfo,
+    globals_defined: HashSet<String>,
+    // Globals referenced before any `var` declaring them, so a runtime
+    // miss can point at the declaration further down.
+    globals_used_early: HashSet<String>,
+    // The constant holding each name used so far, so repeated references
+    // skip building the string value and searching the pool for it.
+    identifiers: HashMap<&'src str, u8>,
+    // Whether to record local names in the chunk for the disassembler
+    // and debugger.
+    debug_info: bool
+}
+
+// The globals a script refers to, collected while compiling. Every
+// reference counts, even in code that never runs.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct CompileInfo {
+    pub globals_read: BTreeSet<String>,
+    pub globals_written: BTreeSet<String>,
+    // Natives the script reads without defining them itself, including
+    // ones that only exist when a feature such as fs is allowed.
+    pub natives_required: BTreeSet<String>
+}
+
+struct Local<'src> {
+    name: &'src str,
+    // `None` while the variable's initializer is being compiled.
+    depth: Option<usize>,
+    // The local's entry in the chunk's local names, if recorded.
+    info: Option<usize>
+}
+
+// A deferred statement is compiled once, where it appears, and run from
+// there when its scope ends. `locals` is how many locals were in scope
+// then; the ones declared after it are popped before it runs.
+struct Deferred {
+    depth: usize,
+    locals: usize
 }
 
 #[repr(u8)]
@@ -28,16 +92,16 @@ enum Precedence {
     Primary
 }
 
-type ParseFn = fn(&mut Parser, bool) -> ErrorResult<()>;
+type ParseFn<'src> = fn(&mut Parser<'src>, bool) -> ErrorResult<()>;
 
-struct ParseRule {
-    pub prefix: Option<ParseFn>,
-    pub infix: Option<ParseFn>,
+struct ParseRule<'src> {
+    pub prefix: Option<ParseFn<'src>>,
+    pub infix: Option<ParseFn<'src>>,
     pub precedence: Precedence,
 }
 
-impl ParseRule {
-    pub fn new(prefix: Option<ParseFn>, infix: Option<ParseFn>, precedence: Precedence) -> Self {
+impl<'src> ParseRule<'src> {
+    pub fn new(prefix: Option<ParseFn<'src>>, infix: Option<ParseFn<'src>>, precedence: Precedence) -> Self {
         ParseRule {
             prefix,
             infix,
@@ -46,31 +110,140 @@ impl ParseRule {
     }
 }
 
-impl Parser {
-    pub fn new(code: &str) -> Self {
+impl<'src> Parser<'src> {
+    pub fn new(code: &'src str) -> Self {
+        Self::with_limits(code, Limits::default())
+    }
+
+    pub fn with_limits(code: &'src str, limits: Limits) -> Self {
         Self {
             tokenizer: Tokenizer::new(code),
             chunk: Chunk::new(),
             previous: Token::new_no_text(TokenType::EndOfFile, 0),
-            current: Token::new_no_text(TokenType::EndOfFile, 0)
+            current: Token::new_no_text(TokenType::EndOfFile, 0),
+            errors: Vec::new(),
+            locals: Vec::new(),
+            defers: Vec::new(),
+            scope_depth: 0,
+            nesting: 0,
+            comparison_end: None,
+            limits: limits.clamped(),
+            defines: HashMap::new(),
+            last_constant: None,
+            skipped_error: false,
+            info: CompileInfo::default(),
+            globals_defined: HashSet::new(),
+            globals_used_early: HashSet::new(),
+            identifiers: HashMap::new(),
+            debug_info: false
         }
     }
 
+    // Names the script reads as fixed values. Each use compiles to a
+    // constant instead of a global lookup, so no global needs to exist,
+    // and declaring or assigning one is a compile error.
+    pub fn with_defines(mut self, defines: HashMap<String, Value>) -> Self {
+        self.defines = defines;
+        self
+    }
+
+    // Records each named local's slot and scope in the chunk, so the
+    // disassembler and debugger can show names instead of slots.
+    pub fn with_debug_info(mut self, debug_info: bool) -> Self {
+        self.debug_info = debug_info;
+        self
+    }
+
+    pub fn parse_str(code: &str) -> ErrorResult<Chunk> {
+        Parser::new(code).parse()
+    }
+
+    // Reports every compile error in the source, one per line. Every call
+    // compiles the whole source from the start, so parsing twice gives
+    // the same result.
     pub fn parse(&mut self) -> ErrorResult<Chunk> {
+        self.compile().map_err(|errors| errors.join("\n"))
+    }
+
+    pub fn parse_with_info(&mut self) -> ErrorResult<(Chunk, CompileInfo)> {
+        let chunk = self.parse()?;
+        let mut info = std::mem::take(&mut self.info);
+
+        let features = FEATURES.iter().flat_map(|(_, natives)| natives.iter());
+
+        info.natives_required = STANDARD_NATIVES.iter()
+            .chain(features)
+            .map(|(name, _)| name.to_string())
+            .filter(|name| info.globals_read.contains(name) && !info.globals_written.contains(name))
+            .collect();
+
+        Ok((chunk, info))
+    }
+
+    // Compiles the source only to collect its diagnostics.
+    pub fn check(source: &str) -> Result<(), Vec<Error>> {
+        Parser::new(source).compile().map(|_| ())
+    }
+
+    fn compile(&mut self) -> Result<Chunk, Vec<Error>> {
+        self.tokenizer.reset();
+        self.previous = Token::new_no_text(TokenType::EndOfFile, 0);
+        self.current = Token::new_no_text(TokenType::EndOfFile, 0);
         self.chunk = Chunk::new();
+        self.errors.clear();
+        self.locals.clear();
+        self.defers.clear();
+        self.scope_depth = 0;
+        self.nesting = 0;
+        self.comparison_end = None;
+        self.last_constant = None;
+        self.skipped_error = false;
+        self.info = CompileInfo::default();
+        self.globals_defined.clear();
+        self.globals_used_early.clear();
+        self.identifiers.clear();
+
+        if let Err(error) = self.advance() {
+            self.errors.push(error);
+        }
 
-        self.advance()?;
-        
-        while !self.match_tok(TokenType::EndOfFile)? {
-            self.declaration()?;
+        loop {
+            match self.match_tok(TokenType::EndOfFile) {
+                Ok(true) => break,
+                Ok(false) => self.declaration(),
+                Err(error) => self.errors.push(error)
+            }
         }
 
-        self.end_compilation()?;
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
+
+        self.end_compilation();
+
+        #[cfg(debug_assertions)]
+        if let Err(error) = self.chunk.verify_stack() {
+            panic!("Compiled bytecode failed verification: {}", error);
+        }
 
         Ok(self.chunk.clone())
     }
 
-    fn declaration(&mut self) -> ErrorResult<()> {
+    // Errors inside a declaration are recorded and parsing resumes at the
+    // next statement boundary, so one mistake doesn't hide the rest.
+    fn declaration(&mut self) {
+        let result = self.try_declaration();
+        let skipped_error = std::mem::replace(&mut self.skipped_error, false);
+
+        if let Err(error) = result {
+            if !skipped_error {
+                self.errors.push(error);
+            }
+            self.synchronize();
+        }
+    }
+
+    fn try_declaration(&mut self) -> ErrorResult<()> {
         if self.match_tok(TokenType::Var)? {
             self.var_declaration()?;
         } else {
@@ -79,33 +252,285 @@ impl Parser {
         Ok(())
     }
 
+    fn synchronize(&mut self) {
+        while self.current.ty != TokenType::EndOfFile {
+            if self.previous.ty == TokenType::Semicolon {
+                return;
+            }
+
+            match self.current.ty {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => ()
+            }
+
+            if let Err(error) = self.advance() {
+                self.errors.push(error);
+            }
+        }
+    }
+
+    // The initializer runs before the variable is declared, so a name it
+    // mentions still means the previous binding: the global being
+    // redefined or the variable a local shadows. If there is none, that
+    // is an undefined variable like any other.
+    //
+    // `var a, b = 1, 2;` declares several variables at once and needs
+    // exactly one value per name.
     fn var_declaration(&mut self) -> ErrorResult<()> {
-        let global = self.parse_variable(Error::from("Expected variable name."))?;
+        let names = self.variable_names()?;
+
+        for name in &names {
+            if self.defines.contains_key(name.text()) {
+                self.error_at(name.clone(), codes::DEFINE_CHANGED, format!("Can't declare '{}'; it is a define.", name.text()))?;
+            }
+        }
 
         if self.match_tok(TokenType::Eq)? {
-            self.expression()?;
+            self.expression_list(names.len())?;
         } else {
-            self.write_byte(OpCode::Nil as u8);
+            for _ in &names {
+                self.write_byte(OpCode::Nil as u8);
+            }
         }
 
         self.consume(TokenType::Semicolon, Error::from("Expected ';' after variable declaration."))?;
 
-        self.define_variable(global);
+        // Globals are defined from the top of the stack down, locals take
+        // their slots in order.
+        if self.scope_depth > 0 {
+            for name in names {
+                self.define_variable(name)?;
+            }
+        } else {
+            for name in names.into_iter().rev() {
+                self.define_variable(name)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // `a, b = b, a;` evaluates every value before assigning any of them,
+    // so it swaps.
+    fn parallel_assignment(&mut self) -> ErrorResult<()> {
+        let targets = self.variable_names()?;
+        self.consume(TokenType::Eq, Error::from("Expected '=' after assignment targets."))?;
+
+        self.expression_list(targets.len())?;
+        self.consume(TokenType::Semicolon, Error::from("Expected ';' after assignment."))?;
+
+        for target in targets.into_iter().rev() {
+            let global = target.text().to_string();
+            if self.defines.contains_key(&global) && self.resolve_local(&global).is_none() {
+                self.error_at(target, codes::DEFINE_CHANGED, format!("Can't assign to '{}'; it is a define.", global))?;
+                continue;
+            }
+
+            let (set_op, arg) = match self.resolve_local(target.text()) {
+                Some(slot) => (OpCode::SetLocal, slot),
+                None => {
+                    self.global_referenced(&global);
+                    self.info.globals_written.insert(global);
+                    (OpCode::SetGlobal, self.identifier_constant(target)?)
+                }
+            };
+
+            self.write_bytes(set_op as u8, arg);
+            self.write_byte(OpCode::Pop as u8);
+        }
+
         Ok(())
     }
 
-    fn parse_variable(&mut self, error_msg: Error) -> ErrorResult<u8> {
-        self.consume(TokenType::Ident, error_msg)?;
+    fn variable_names(&mut self) -> ErrorResult<Vec<Token<'src>>> {
+        let mut names: Vec<Token<'src>> = Vec::new();
+
+        loop {
+            self.consume(TokenType::Ident, Error::from("Expected variable name."))?;
+
+            if names.iter().any(|name| name.text() == self.previous.text()) {
+                self.error(codes::DUPLICATE_VARIABLE, format!("Variable '{}' appears more than once.", self.previous.text()))?;
+            }
+            names.push(self.previous.clone());
+
+            if !self.match_tok(TokenType::Comma)? {
+                return Ok(names)
+            }
+        }
+    }
+
+    // A wrong count caused by a decimal comma, as in `var x = 1,5;`, is
+    // reported as that instead.
+    fn expression_list(&mut self, expected: usize) -> ErrorResult<()> {
+        let mut count = 0;
+        let mut decimal_comma = None;
+
+        loop {
+            self.expression()?;
+            count += 1;
+
+            if decimal_comma.is_none() {
+                decimal_comma = self.decimal_comma().map(|message| (self.current.clone(), message));
+            }
+
+            if !self.match_tok(TokenType::Comma)? {
+                break
+            }
+        }
+
+        match decimal_comma {
+            Some((comma, message)) if expected != count => self.error_at(comma, codes::MALFORMED_NUMBER, message),
+            _ if expected != count => self.error(codes::VALUE_COUNT, format!("Expected {} values but got {}.", expected, count)),
+            _ => Ok(())
+        }
+    }
+
+    // A suggestion if the current token is a comma between two digits
+    // right after a number, as in `1,5`.
+    fn decimal_comma(&mut self) -> Option<String> {
+        if self.current.ty != TokenType::Comma || self.previous.ty != TokenType::Number || !self.tokenizer.is_decimal_comma() {
+            return None
+        }
 
-        Ok(self.identifier_constant(self.previous.clone())?)
+        let fraction = self.tokenizer.peek_token();
+        Some(format!("Numbers use '.' for decimals; did you mean {}.{}?", self.previous.source_text(), fraction.source_text()))
     }
 
-    fn identifier_constant(&mut self, identifier_token: Token) -> ErrorResult<u8> {
-        self.make_constant(Value::String(identifier_token.text))
+    fn identifier_constant(&mut self, identifier_token: Token<'src>) -> ErrorResult<u8> {
+        let name = identifier_token.source_text();
+        if let Some(constant) = self.identifiers.get(name) {
+            return Ok(*constant)
+        }
+
+        let constant = self.make_constant(Value::String(name.to_string().into()))?;
+        self.identifiers.insert(name, constant);
+        Ok(constant)
     }
 
-    fn define_variable(&mut self, global: u8) {
+    fn declare_local(&mut self, name: &'src str) -> ErrorResult<()> {
+        for local in self.locals.iter().rev() {
+            if local.depth.is_some_and(|depth| depth < self.scope_depth) {
+                break;
+            }
+
+            if local.name == name {
+                return self.error(codes::DUPLICATE_VARIABLE, "Already a variable with this name in this scope.".to_string());
+            }
+        }
+
+        if self.locals.len() >= self.limits.locals {
+            return self.error(codes::COMPILE_LIMIT, format!("Too many local variables in scope (limit {}).", self.limits.locals));
+        }
+
+        self.locals.push(Local {
+            name,
+            depth: None,
+            info: None
+        });
+
+        Ok(())
+    }
+
+    fn define_variable(&mut self, name: Token<'src>) -> ErrorResult<()> {
+        if self.scope_depth > 0 {
+            self.declare_local(name.source_text())?;
+            self.mark_initialized();
+            return Ok(())
+        }
+
+        if self.globals_used_early.remove(name.text()) {
+            self.chunk.add_later_definition(name.text(), name.line);
+        }
+
+        self.globals_defined.insert(name.text().to_string());
+        self.info.globals_written.insert(name.text().to_string());
+        let global = self.identifier_constant(name)?;
         self.write_bytes(OpCode::DefineGlobal as u8, global);
+        Ok(())
+    }
+
+    fn global_referenced(&mut self, name: &str) {
+        if !self.globals_defined.contains(name) {
+            self.globals_used_early.insert(name.to_string());
+        }
+    }
+
+    // Hidden locals have names with spaces and aren't recorded.
+    fn mark_initialized(&mut self) {
+        let slot = self.locals.len().saturating_sub(1);
+        let start = self.chunk.len();
+        let line = self.previous.line;
+
+        if let Some(local) = self.locals.last_mut() {
+            local.depth = Some(self.scope_depth);
+
+            if self.debug_info && !local.name.contains(' ') {
+                local.info = Some(self.chunk.add_local_info(LocalInfo {
+                    slot: slot as u8,
+                    name: local.name.to_string(),
+                    line,
+                    start,
+                    end: start
+                }));
+            }
+        }
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<u8> {
+        self.locals.iter()
+            .rposition(|local| local.name == name)
+            .map(|slot| slot as u8)
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.write_defers();
+        self.scope_depth -= 1;
+
+        let kept = self.locals.iter()
+            .rposition(|local| local.depth.is_some_and(|depth| depth <= self.scope_depth))
+            .map_or(0, |slot| slot + 1);
+        self.pop_locals(kept);
+    }
+
+    // Runs the scope's deferred statements, newest first.
+    fn write_defers(&mut self) {
+        while self.defers.last().is_some_and(|deferred| deferred.depth == self.scope_depth) {
+            let deferred = self.defers.pop().unwrap();
+            self.pop_locals(deferred.locals);
+            self.write_byte(OpCode::RunDefer as u8);
+        }
+    }
+
+    // Pops locals until `kept` remain.
+    fn pop_locals(&mut self, kept: usize) {
+        let mut count = 0;
+        while self.locals.len() > kept {
+            if let Some(info) = self.locals.pop().and_then(|local| local.info) {
+                self.chunk.end_local_info(info, self.chunk.len());
+            }
+            count += 1;
+        }
+
+        // At most 256 locals exist, and a count of 256 takes two pops.
+        while count > 0 {
+            let popped = count.min(u8::MAX as usize);
+            match popped {
+                1 => self.write_byte(OpCode::Pop as u8),
+                _ => self.write_bytes(OpCode::PopN as u8, popped as u8)
+            }
+            count -= popped;
+        }
     }
 
     fn variable(&mut self, can_assign: bool) -> ErrorResult<()> {
@@ -113,22 +538,72 @@ impl Parser {
         Ok(())
     }
 
-    fn named_variable(&mut self, name: Token, can_assign: bool) -> ErrorResult<()>  {
-        let arg = self.identifier_constant(name)?;
+    fn named_variable(&mut self, name: Token<'src>, can_assign: bool) -> ErrorResult<()>  {
+        let global = name.text().to_string();
+        let (get_op, set_op, arg) = match self.resolve_local(name.text()) {
+            Some(slot) => (OpCode::GetLocal, OpCode::SetLocal, slot),
+            None if self.defines.contains_key(&global) => {
+                if can_assign && self.check_tok(TokenType::Eq) {
+                    return self.error_at(name, codes::DEFINE_CHANGED, format!("Can't assign to '{}'; it is a define.", global))
+                }
+
+                let value = self.defines[&global].clone();
+                return self.write_constant(value)
+            },
+            None => {
+                self.global_referenced(&global);
+                (OpCode::GetGlobal, OpCode::SetGlobal, self.identifier_constant(name)?)
+            }
+        };
+
         if can_assign && self.match_tok(TokenType::Eq)? {
             self.expression()?;
-            self.write_bytes(OpCode::SetGlobal as u8, arg);
+            self.write_bytes(set_op as u8, arg);
+
+            if set_op == OpCode::SetGlobal {
+                self.info.globals_written.insert(global);
+            }
         } else {
-            self.write_bytes(OpCode::GetGlobal as u8, arg);
+            self.write_bytes(get_op as u8, arg);
+
+            if get_op == OpCode::GetGlobal {
+                self.info.globals_read.insert(global);
+            }
         }
         Ok(())
     }
     
     fn statement(&mut self) -> ErrorResult<()> {
+        self.nested(Self::nested_statement)
+    }
+
+    // Guards the recursive parts of the grammar so deeply nested input
+    // gets a compile error instead of overflowing the stack.
+    fn nested(&mut self, parse: impl FnOnce(&mut Self) -> ErrorResult<()>) -> ErrorResult<()> {
+        if self.nesting >= self.limits.nesting {
+            return self.error_at_current(codes::COMPILE_LIMIT, format!("Nesting too deep (limit {}).", self.limits.nesting))
+        }
+
+        self.nesting += 1;
+        let result = parse(self);
+        self.nesting -= 1;
+        result
+    }
+
+    fn nested_statement(&mut self) -> ErrorResult<()> {
         if self.match_tok(TokenType::Print)? {
             self.print_statement()?;
+        } else if self.match_tok(TokenType::For)? {
+            self.for_statement()?;
+        } else if self.match_tok(TokenType::Defer)? {
+            self.defer_statement()?;
         } else if self.match_tok(TokenType::LBrace)? {
-            self.block()?;
+            self.begin_scope();
+            let result = self.block();
+            self.end_scope();
+            result?;
+        } else if self.check_tok(TokenType::Ident) && self.tokenizer.peek_token().ty == TokenType::Comma {
+            self.parallel_assignment()?;
         } else {
             self.expression_statement()?;
         }
@@ -137,12 +612,174 @@ impl Parser {
     }
 
     fn block(&mut self) -> ErrorResult<()> {
-        while !self.check(TokenType::RBrace) && !self.check(TokenType::EndOfFile) {
-            self.declaration()?;
+        while !self.check_tok(TokenType::RBrace) && !self.check_tok(TokenType::EndOfFile) {
+            self.declaration();
+        }
+
+        self.consume(TokenType::RBrace, Error::from("Expect '}' after block."))?;
+
+        Ok(())
+    }
+
+    // `defer stmt;` runs stmt when the enclosing scope ends, with the
+    // defers of a scope running in reverse order. Names in stmt resolve
+    // where it appears, so a local declared after it can't shadow them.
+    // When a runtime error ends the script, the defers still pending run
+    // before the error is reported.
+    fn defer_statement(&mut self) -> ErrorResult<()> {
+        if self.scope_depth == 0 {
+            return self.error(codes::MISPLACED_DEFER, "Can't use 'defer' outside a block.".to_string())
+        }
+
+        if self.check_tok(TokenType::Defer) {
+            return self.error(codes::MISPLACED_DEFER, "Can't defer a 'defer' statement.".to_string())
+        }
+
+        let deferred = Deferred {
+            depth: self.scope_depth,
+            locals: self.locals.len()
+        };
+
+        let skip = self.write_jump(OpCode::Defer);
+        self.statement()?;
+        self.write_byte(OpCode::EndDefer as u8);
+        self.patch_jump(skip)?;
+
+        self.defers.push(deferred);
+        Ok(())
+    }
+
+    // `for i in start..end body` counts from start up to, but not
+    // including, end. Both bounds are evaluated once and must be whole
+    // numbers with start <= end, no further than 2^53 from zero, which
+    // the CheckRange instruction verifies at runtime. Each iteration binds a fresh `i`, so assigning to it
+    // doesn't affect the iteration.
+    //
+    // `for c in string body` binds each character of the string in turn.
+    fn for_statement(&mut self) -> ErrorResult<()> {
+        self.begin_scope();
+        let result = self.for_loop();
+        self.end_scope();
+        result
+    }
+
+    fn for_loop(&mut self) -> ErrorResult<()> {
+        self.consume(TokenType::Ident, Error::from("Expected loop variable name after 'for'."))?;
+        let name = self.previous.source_text();
+        self.consume(TokenType::In, Error::from("Expected 'in' after loop variable."))?;
+
+        self.expression()?;
+
+        if self.match_tok(TokenType::DotDot)? {
+            self.for_range(name)
+        } else {
+            self.for_each(name)
+        }
+    }
+
+    fn for_range(&mut self, name: &'src str) -> ErrorResult<()> {
+        // The counter and end bound live in hidden locals whose names
+        // can't clash with identifiers.
+        self.declare_local("for counter")?;
+        self.mark_initialized();
+        let counter = (self.locals.len() - 1) as u8;
+
+        self.expression()?;
+        self.declare_local("for end")?;
+        self.mark_initialized();
+        let end = (self.locals.len() - 1) as u8;
+
+        self.write_byte(OpCode::CheckRange as u8);
+
+        let loop_start = self.chunk.len();
+        self.write_bytes(OpCode::GetLocal as u8, counter);
+        self.write_bytes(OpCode::GetLocal as u8, end);
+        self.write_byte(OpCode::Less as u8);
+        let exit_jump = self.write_jump(OpCode::JumpIfFalse);
+        self.write_byte(OpCode::Pop as u8);
+
+        self.begin_scope();
+        self.write_bytes(OpCode::GetLocal as u8, counter);
+        self.declare_local(name)?;
+        self.mark_initialized();
+        let body = self.statement();
+        self.end_scope();
+        body?;
+
+        self.write_bytes(OpCode::GetLocal as u8, counter);
+        self.write_constant(Value::Number(1.0))?;
+        self.write_byte(OpCode::Add as u8);
+        self.write_bytes(OpCode::SetLocal as u8, counter);
+        self.write_byte(OpCode::Pop as u8);
+        self.write_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.write_byte(OpCode::Pop as u8);
+        Ok(())
+    }
+
+    // The iterated value and the position in it live in two adjacent
+    // hidden locals. IterNext pushes the next item and true, or nil and
+    // false once the value is exhausted.
+    fn for_each(&mut self, name: &'src str) -> ErrorResult<()> {
+        self.declare_local("for iterable")?;
+        self.mark_initialized();
+        let iterable = (self.locals.len() - 1) as u8;
+
+        self.write_constant(Value::Number(0.0))?;
+        self.declare_local("for index")?;
+        self.mark_initialized();
+
+        let loop_start = self.chunk.len();
+        self.write_bytes(OpCode::IterNext as u8, iterable);
+        let exit_jump = self.write_jump(OpCode::JumpIfFalse);
+        self.write_byte(OpCode::Pop as u8);
+
+        self.begin_scope();
+        self.declare_local(name)?;
+        self.mark_initialized();
+        let body = self.statement();
+        self.end_scope();
+        body?;
+
+        self.write_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.write_bytes(OpCode::PopN as u8, 2);
+        Ok(())
+    }
+
+    fn write_jump(&mut self, opcode: OpCode) -> usize {
+        self.write_byte(opcode as u8);
+        self.write_bytes(0xff, 0xff);
+        self.chunk.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) -> ErrorResult<()> {
+        // -2 to adjust for the jump offset itself.
+        let jump = self.chunk.len() - offset - 2;
+
+        if jump > self.limits.jump {
+            self.error(codes::COMPILE_LIMIT, format!("Too much code to jump over (limit {} bytes).", self.limits.jump))?;
         }
 
-        self.consume(TokenType::RBrace, Error::from("Expect '(' after block."))?;
+        let [high, low] = (jump as u16).to_be_bytes();
+        self.chunk.patch_byte(offset, high);
+        self.chunk.patch_byte(offset + 1, low);
+        Ok(())
+    }
+
+    fn write_loop(&mut self, loop_start: usize) -> ErrorResult<()> {
+        self.write_byte(OpCode::Loop as u8);
+
+        // +2 to jump over the loop's own offset.
+        let offset = self.chunk.len() - loop_start + 2;
+        if offset > self.limits.jump {
+            self.error(codes::COMPILE_LIMIT, format!("Loop body too large (limit {} bytes).", self.limits.jump))?;
+        }
 
+        let [high, low] = (offset as u16).to_be_bytes();
+        self.write_bytes(high, low);
         Ok(())
     }
 
@@ -160,23 +797,29 @@ impl Parser {
         Ok(())
     }
 
+    // Error tokens are recorded and skipped, so a bad character costs one
+    // diagnostic and parsing carries on with the next real token.
     fn advance(&mut self) -> ErrorResult<()> {
         self.previous = self.current.clone();
 
         loop {
             self.current = self.tokenizer.scan_token();
 
-            if &self.current.ty == &TokenType::Error {
-                let txt = self.current.text.clone();
-                return self.error_at_current(txt);
-            } else {
+            if self.current.ty != TokenType::Error {
                 return Ok(())
             }
+
+            let code = self.current.code.unwrap_or(codes::UNEXPECTED_CHARACTER);
+            let txt = self.current.text().to_string();
+            if let Err(error) = self.error_at_current(code, txt) {
+                self.errors.push(error);
+            }
+            self.skipped_error = true;
         }
     }
 
     fn match_tok(&mut self, ty: TokenType) -> ErrorResult<bool> {
-        if !self.check(ty) {
+        if !self.check_tok(ty) {
             return Ok(false);
         }
 
@@ -184,25 +827,20 @@ impl Parser {
         Ok(true)
     }
 
-    fn check(&self, ty: TokenType) -> bool {
+    fn check_tok(&self, ty: TokenType) -> bool {
         self.current.ty == ty
     }
 
-    fn error_at_current(&mut self, message: String) -> ErrorResult<()> {
-        self.error_at(self.current.clone(), message)
+    fn error_at_current(&mut self, code: &ErrorCode, message: String) -> ErrorResult<()> {
+        self.error_at(self.current.clone(), code, message)
     }
 
-    fn error(&mut self, message: String) -> ErrorResult<()> {
-        self.error_at(self.previous.clone(), message)
+    fn error(&mut self, code: &ErrorCode, message: String) -> ErrorResult<()> {
+        self.error_at(self.previous.clone(), code, message)
     }
 
-    #[allow(unused_must_use)]
-    fn error_at(&mut self, token: Token, message: String) -> ErrorResult<()> {
-        let mut error_string = Error::new();
-        error_string.write_str(&format!("[line {}] Error: ", token.line));
-        error_string.write_str(&message);
-
-        Err(error_string)
+    fn error_at(&mut self, token: Token<'src>, code: &ErrorCode, message: String) -> ErrorResult<()> {
+        Err(format!("[line {}] Error {}: {}", token.line, code.code, message))
     }
 
     fn expression(&mut self) -> ErrorResult<()> {
@@ -225,13 +863,42 @@ impl Parser {
             return Ok(())
         }
 
-        self.error_at_current(message)
+        let found = self.current.describe();
+
+        // A missing ';' is usually noticed at the start of the next line,
+        // so point at the line that actually needs it.
+        if ty == TokenType::Semicolon && self.current.line > self.previous.line {
+            let line = self.previous.line;
+            let message = format!("Expected ';' at end of line {}, but found {}.", line, found);
+            return self.error_at(self.previous.clone(), codes::MISSING_SEMICOLON, message)
+        }
+
+        // `1,5` scans as two numbers, so outside a list it ends up here.
+        if ty == TokenType::Semicolon {
+            if let Some(message) = self.decimal_comma() {
+                return self.error_at_current(codes::MALFORMED_NUMBER, message)
+            }
+        }
+
+        let code = if ty == TokenType::Semicolon { codes::MISSING_SEMICOLON } else { codes::UNEXPECTED_TOKEN };
+        let expected = message.strip_suffix('.').unwrap_or(&message);
+        self.error_at_current(code, format!("{}, but found {}.", expected, found))
     }
 
 
     fn number(&mut self, _: bool) -> ErrorResult<()> {
         if let TokenType::Number = self.previous.ty {
-            let v = self.previous.text.parse().unwrap();
+            let text = self.previous.source_text();
+            let v: f64 = match text.parse() {
+                Ok(v) => v,
+                Err(_) => return self.error(codes::MALFORMED_NUMBER, format!("Invalid number literal '{}'.", text))
+            };
+
+            // Literals past f64's range would otherwise quietly become inf.
+            if v.is_infinite() {
+                return self.error(codes::MALFORMED_NUMBER, format!("Number literal '{}' is too large.", text))
+            }
+
             self.write_constant(Value::Number(v))?;
             return Ok(())
         }
@@ -240,33 +907,85 @@ impl Parser {
     }
 
     fn write_constant(&mut self, value: Value) -> ErrorResult<()> {
-        let value_byte = self.make_constant(value)?;
-        self.write_bytes(OpCode::Constant as u8, value_byte);
+        self.write_constant_on(value, self.previous.line)
+    }
+
+    // Whole numbers from 0 to 255 are loaded with ZERO, ONE or SMALL_INT
+    // and take no slot in the constant pool. -0 keeps its sign in the pool.
+    fn write_constant_on(&mut self, value: Value, line: usize) -> ErrorResult<()> {
+        self.last_constant = Some(self.chunk.len());
+
+        let small = match value {
+            Value::Number(number) if number.fract() == 0.0 && number.is_sign_positive() && number <= u8::MAX as f64 => {
+                Some(number as u8)
+            },
+            _ => None
+        };
+
+        let bytes = match small {
+            Some(0) => vec![OpCode::Zero as u8],
+            Some(1) => vec![OpCode::One as u8],
+            Some(small) => vec![OpCode::SmallInt as u8, small],
+            None => vec![OpCode::Constant as u8, self.make_constant(value)?]
+        };
+
+        for byte in bytes {
+            self.chunk.write_byte(Byte::new(byte, line));
+        }
+
         Ok(())
     }
 
+    // The number a single constant load starting at `start` pushes, if
+    // that instruction is all the code from `start` on.
+    fn constant_number(&self, start: usize) -> Option<f64> {
+        if self.last_constant != Some(start) {
+            return None
+        }
+
+        let decoded = self.chunk.instructions_from(start).next()?;
+        if start + decoded.instruction.size() != self.chunk.len() {
+            return None
+        }
+
+        match decoded.instruction {
+            Instruction::Constant { value: Value::Number(number), .. } => Some(number),
+            Instruction::Zero => Some(0.0),
+            Instruction::One => Some(1.0),
+            Instruction::SmallInt { value } => Some(value as f64),
+            _ => None
+        }
+    }
+
     fn make_constant(&mut self, value: Value) -> ErrorResult<u8> {
-        let constant = self.chunk.write_value(value);
+        let constant = match self.chunk.find_value(&value) {
+            Some(constant) => constant,
+            None => self.chunk.write_value(value)
+        };
 
-        if constant > u8::MAX {
-            self.error("Too many constants in one chunk.".to_string())?;
+        if constant >= self.limits.constants {
+            self.error(codes::COMPILE_LIMIT, format!("Too many constants in one chunk (limit {}).", self.limits.constants))?;
         }
 
-        Ok(constant)
+        Ok(constant as u8)
     }
 
-    fn end_compilation(&mut self) -> ErrorResult<()> {
+    fn end_compilation(&mut self) {
         self.write_byte(OpCode::Return as u8);
-        Ok(())
     }
 
     fn unary(&mut self, _: bool) -> ErrorResult<()> {
         let op_type = self.previous.ty.clone();
+        let operand_start = self.chunk.len();
 
         self.parse_precedence(Precedence::Unary as u8)?;
 
         match op_type {
-            TokenType::Minus => self.write_byte(OpCode::Negate as u8),
+            TokenType::Minus => {
+                if !self.fold_negation(operand_start)? {
+                    self.write_byte(OpCode::Negate as u8);
+                }
+            },
             TokenType::Bang => self.write_byte(OpCode::Not as u8),
             _ => unreachable!()
         }
@@ -274,34 +993,276 @@ impl Parser {
         Ok(())
     }
 
+    // Rewrites a negated number constant into a single negative constant,
+    // returning whether the operand could be folded.
+    fn fold_negation(&mut self, operand_start: usize) -> ErrorResult<bool> {
+        let value = match self.constant_number(operand_start) {
+            Some(value) => value,
+            None => return Ok(false)
+        };
+
+        self.replace_with_constant(operand_start, Value::Number(-value))?;
+        Ok(true)
+    }
+
+    // Replaces the code from `start` on with one constant load on the
+    // line of the first replaced byte.
+    fn replace_with_constant(&mut self, start: usize, value: Value) -> ErrorResult<()> {
+        let line = self.chunk.get_byte(start).line;
+        self.chunk.truncate(start);
+        self.write_constant_on(value, line)
+    }
+
+    // Folds arithmetic on two number constants, such as `LIMIT * 2` with
+    // LIMIT defined, into a single constant.
+    fn fold_arithmetic(&mut self, left_start: usize, right_start: usize, left: f64, op_type: &TokenType) -> ErrorResult<bool> {
+        let right = match self.constant_number(right_start) {
+            Some(right) => right,
+            None => return Ok(false)
+        };
+
+        let result = match op_type {
+            TokenType::Plus => left + right,
+            TokenType::Minus => left - right,
+            TokenType::Asterisk => left * right,
+            TokenType::Slash => left / right,
+            _ => return Ok(false)
+        };
+
+        self.replace_with_constant(left_start, Value::Number(result))?;
+        Ok(true)
+    }
+
     fn binary(&mut self, _: bool) -> ErrorResult<()> {
         let op_type = self.previous.ty.clone();
+        let is_comparison = [TokenType::Greater, TokenType::GreaterEq, TokenType::Less, TokenType::LessEq]
+            .contains(&op_type);
+
+        // `0 < x < 10` would compare the bool from `0 < x` with 10.
+        if is_comparison && self.comparison_end == Some(self.chunk.len()) {
+            return self.error(codes::CHAINED_COMPARISON, "Chained comparisons are not supported; compare each pair separately.".to_string())
+        }
+
+        let left_constant = self.last_constant
+            .and_then(|start| Some((start, self.constant_number(start)?)));
+        let right_start = self.chunk.len();
+
         let parse_rule = Self::get_parse_rule(op_type.clone());
         self.parse_precedence(parse_rule.precedence as u8 + 1)?;
 
+        if let Some((left_start, left)) = left_constant {
+            if self.fold_arithmetic(left_start, right_start, left, &op_type)? {
+                return Ok(())
+            }
+        }
+
         match op_type {
             TokenType::Plus => self.write_byte(OpCode::Add as u8),
             TokenType::Minus => self.write_byte(OpCode::Subtract as u8),
             TokenType::Asterisk => self.write_byte(OpCode::Multiply as u8),
             TokenType::Slash => self.write_byte(OpCode::Divide as u8),
-            TokenType::BangEq => self.write_bytes(OpCode::Equal as u8, OpCode::Not as u8),
+            TokenType::BangEq => self.write_byte(OpCode::NotEqual as u8),
             TokenType::EqEq => self.write_byte(OpCode::Equal as u8),
             TokenType::Greater => self.write_byte(OpCode::Greater as u8),
-            TokenType::GreaterEq => self.write_bytes(OpCode::Less as u8, OpCode::Not as u8),
+            TokenType::GreaterEq => self.write_byte(OpCode::GreaterEqual as u8),
             TokenType::Less => self.write_byte(OpCode::Less as u8),
-            TokenType::LessEq => self.write_bytes(OpCode::Greater as u8, OpCode::Not as u8),
+            TokenType::LessEq => self.write_byte(OpCode::LessEqual as u8),
+            TokenType::In => self.write_byte(OpCode::In as u8),
             _ => unreachable!()   
         }
 
+        if is_comparison {
+            self.comparison_end = Some(self.chunk.len());
+        }
+
+        Ok(())
+    }
+
+    fn call(&mut self, _: bool) -> ErrorResult<()> {
+        let arg_count = self.argument_list()?;
+        self.write_bytes(OpCode::Call as u8, arg_count);
+        Ok(())
+    }
+
+    fn dot(&mut self, _: bool) -> ErrorResult<()> {
+        self.consume(TokenType::Ident, Error::from("Expected method name after '.'."))?;
+        let name = self.identifier_constant(self.previous.clone())?;
+
+        // Only method calls exist so far, so `value.name` on its own is
+        // rejected here rather than failing on whatever token follows.
+        if !self.check_tok(TokenType::LParen) {
+            return self.error(codes::UNSUPPORTED_SYNTAX, "Property access is not supported yet.".to_string())
+        }
+
+        self.consume(TokenType::LParen, Error::from("Expected '(' after method name."))?;
+        let arg_count = self.argument_list()?;
+
+        self.write_bytes(OpCode::Invoke as u8, name);
+        self.write_byte(arg_count);
+        Ok(())
+    }
+
+    // `value[index]`, `value[start..end]` or `value[index] = new`. Only
+    // strings can be indexed so far, and they reject assignment at
+    // runtime.
+    fn index(&mut self, can_assign: bool) -> ErrorResult<()> {
+        self.expression()?;
+
+        if self.match_tok(TokenType::DotDot)? {
+            self.expression()?;
+            self.consume(TokenType::RBracket, Error::from("Expected ']' after slice."))?;
+            self.write_byte(OpCode::Slice as u8);
+            return Ok(())
+        }
+
+        self.consume(TokenType::RBracket, Error::from("Expected ']' after index."))?;
+
+        if can_assign && self.match_tok(TokenType::Eq)? {
+            self.expression()?;
+            self.write_byte(OpCode::SetIndex as u8);
+        } else {
+            self.write_byte(OpCode::Index as u8);
+        }
+
         Ok(())
     }
 
+    // `receiver?.method(args)` leaves nil on the stack when the receiver
+    // is nil. The jump skips the rest of the call chain as well, so
+    // neither the arguments nor any later calls are evaluated.
+    fn optional_dot(&mut self, _: bool) -> ErrorResult<()> {
+        let nil_jump = self.write_jump(OpCode::JumpIfNil);
+        self.dot(false)?;
+
+        while [TokenType::Dot, TokenType::QuestionDot, TokenType::LParen, TokenType::LBracket].contains(&self.current.ty) {
+            self.advance()?;
+            let infix_rule = Self::get_parse_rule(self.previous.ty.clone()).infix;
+            infix_rule.unwrap()(self, false)?;
+        }
+
+        self.patch_jump(nil_jump)
+    }
+
+    fn stray_dot(&mut self, _: bool) -> ErrorResult<()> {
+        if self.check_tok(TokenType::Number) {
+            return self.error(codes::MALFORMED_NUMBER, "Numbers need a digit before the decimal point, e.g. '0.5'.".to_string())
+        }
+
+        self.error(codes::EXPECTED_EXPRESSION, "Expected expression before '.'.".to_string())
+    }
+
+    // There are no classes yet, so neither keyword is ever inside one.
+    fn this(&mut self, _: bool) -> ErrorResult<()> {
+        self.error(codes::UNSUPPORTED_SYNTAX, "Can't use 'this' outside of a class.".to_string())
+    }
+
+    fn super_(&mut self, _: bool) -> ErrorResult<()> {
+        self.error(codes::UNSUPPORTED_SYNTAX, "Can't use 'super' outside of a class.".to_string())
+    }
+
+    fn argument_list(&mut self) -> ErrorResult<u8> {
+        let mut arg_count: usize = 0;
+
+        if !self.check_tok(TokenType::RParen) {
+            loop {
+                self.expression()?;
+
+                if arg_count == self.limits.arguments {
+                    self.error(codes::COMPILE_LIMIT, format!("Can't have more than {} arguments.", self.limits.arguments))?;
+                }
+
+                arg_count += 1;
+
+                if !self.match_tok(TokenType::Comma)? {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RParen, Error::from("Expected ')' after arguments."))?;
+        Ok(arg_count as u8)
+    }
+
     fn grouping(&mut self, _: bool) -> ErrorResult<()> {
         self.expression()?;
         self.consume(TokenType::RParen, Error::from("Expected ')' after expression."))?;
+
+        // Parentheses make comparing a comparison's result deliberate.
+        self.comparison_end = None;
+        Ok(())
+    }
+
+    // Each arm compares a copy of the subject with its pattern and jumps
+    // to the next arm on a mismatch. The final `_` arm is required, so
+    // exactly one arm's value is left on the stack.
+    fn match_expression(&mut self, _: bool) -> ErrorResult<()> {
+        self.expression()?;
+        self.consume(TokenType::LBrace, Error::from("Expected '{' after match subject."))?;
+
+        let mut end_jumps = Vec::new();
+
+        loop {
+            if self.check_tok(TokenType::Ident) && self.current.text() == "_" {
+                self.advance()?;
+                self.consume(TokenType::FatArrow, Error::from("Expected '=>' after pattern."))?;
+                self.write_byte(OpCode::Pop as u8);
+                self.expression()?;
+                self.match_tok(TokenType::Comma)?;
+                break;
+            }
+
+            if self.check_tok(TokenType::RBrace) || self.check_tok(TokenType::EndOfFile) {
+                return self.error_at_current(codes::MATCH_WITHOUT_DEFAULT, Error::from("A match needs a final '_' arm."))
+            }
+
+            self.write_byte(OpCode::Dup as u8);
+            self.pattern()?;
+            self.write_byte(OpCode::Equal as u8);
+            self.consume(TokenType::FatArrow, Error::from("Expected '=>' after pattern."))?;
+
+            let next_arm = self.write_jump(OpCode::JumpIfFalse);
+            self.write_bytes(OpCode::PopN as u8, 2);
+            self.expression()?;
+            end_jumps.push(self.write_jump(OpCode::Jump));
+
+            self.patch_jump(next_arm)?;
+            self.write_byte(OpCode::Pop as u8);
+
+            if !self.match_tok(TokenType::Comma)? {
+                return self.error_at_current(codes::MATCH_WITHOUT_DEFAULT, Error::from("A match needs a final '_' arm."))
+            }
+        }
+
+        self.consume(TokenType::RBrace, Error::from("Expected '}' after the '_' arm."))?;
+
+        for jump in end_jumps {
+            self.patch_jump(jump)?;
+        }
+
+        // The last arm's value may be a lone constant, but the match as a
+        // whole isn't one and mustn't be folded.
+        self.last_constant = None;
+        self.comparison_end = None;
         Ok(())
     }
 
+    fn pattern(&mut self) -> ErrorResult<()> {
+        self.advance()?;
+
+        match self.previous.ty {
+            TokenType::Number => self.number(false),
+            TokenType::String => self.string(false),
+            TokenType::True | TokenType::False | TokenType::Nil => self.literal(false),
+            TokenType::Minus if self.check_tok(TokenType::Number) => {
+                let start = self.chunk.len();
+                self.advance()?;
+                self.number(false)?;
+                self.fold_negation(start).map(|_| ())
+            },
+            _ => self.error(codes::UNEXPECTED_TOKEN, Error::from("Expected a literal pattern or '_'."))
+        }
+    }
+
     fn literal(&mut self, _: bool) -> ErrorResult<()> {
         match self.previous.ty {
             TokenType::False => self.write_byte(OpCode::False as u8),
@@ -314,10 +1275,10 @@ impl Parser {
     }
 
     fn string(&mut self, _: bool) -> ErrorResult<()> {
-        let string: String;
+        let string: Rc<String>;
 
         if let TokenType::String = self.previous.ty.clone() {
-            string = self.previous.text.clone();
+            string = self.previous.source_text().to_string().into();
         } else {
             unreachable!()
         }
@@ -327,19 +1288,23 @@ impl Parser {
     }
 
     fn parse_precedence(&mut self, precedence: u8) -> ErrorResult<()> {
+        self.nested(|parser| parser.nested_precedence(precedence))
+    }
+
+    fn nested_precedence(&mut self, precedence: u8) -> ErrorResult<()> {
         self.advance()?;
 
         let prefix_rule = Self::get_parse_rule(self.previous.ty.clone()).prefix;
 
         if prefix_rule.is_none() {
-            return self.error("Expected expression.".to_string())
+            return self.error(codes::EXPECTED_EXPRESSION, "Expected expression.".to_string())
         }
 
         let can_assign = precedence <= Precedence::Assignment as u8;
         prefix_rule.unwrap()(self, can_assign)?;
 
         while precedence
-            <= Self::get_parse_rule(self.current.clone().ty).precedence as u8
+            <= Self::get_parse_rule(self.current.ty.clone()).precedence as u8
         {
             self.advance()?;
             let infix_rule = Self::get_parse_rule(self.previous.ty.clone()).infix;
@@ -349,9 +1314,12 @@ impl Parser {
         Ok(())
     }
 
-    fn get_parse_rule(t: TokenType) -> ParseRule {
+    fn get_parse_rule(t: TokenType) -> ParseRule<'src> {
         match t {
-            TokenType::LParen => ParseRule::new(Some(Self::grouping), None, Precedence::None),
+            TokenType::LParen => ParseRule::new(Some(Self::grouping), Some(Self::call), Precedence::Call),
+            TokenType::Dot => ParseRule::new(Some(Self::stray_dot), Some(Self::dot), Precedence::Call),
+            TokenType::QuestionDot => ParseRule::new(None, Some(Self::optional_dot), Precedence::Call),
+            TokenType::LBracket => ParseRule::new(None, Some(Self::index), Precedence::Call),
             TokenType::Minus => ParseRule::new(Some(Self::unary), Some(Self::binary), Precedence::Term),
             TokenType::Plus => ParseRule::new(None, Some(Self::binary), Precedence::Term),
             TokenType::Semicolon => ParseRule::new(None, None, Precedence::None),
@@ -365,12 +1333,99 @@ impl Parser {
             TokenType::GreaterEq => ParseRule::new(None, Some(Self::binary), Precedence::Comparison),
             TokenType::Less => ParseRule::new(None, Some(Self::binary), Precedence::Comparison),
             TokenType::LessEq => ParseRule::new(None, Some(Self::binary), Precedence::Comparison),
+            TokenType::In => ParseRule::new(None, Some(Self::binary), Precedence::Comparison),
             TokenType::False => ParseRule::new(Some(Self::literal), None, Precedence::None),
             TokenType::True => ParseRule::new(Some(Self::literal), None, Precedence::None),
             TokenType::Nil => ParseRule::new(Some(Self::literal), None, Precedence::None),
             TokenType::String => ParseRule::new(Some(Self::string), None, Precedence::None),
             TokenType::Ident => ParseRule::new(Some(Self::variable), None, Precedence::None),
+            TokenType::This => ParseRule::new(Some(Self::this), None, Precedence::None),
+            TokenType::Super => ParseRule::new(Some(Self::super_), None, Precedence::None),
+            TokenType::Match => ParseRule::new(Some(Self::match_expression), None, Precedence::None),
             _ => ParseRule::new(None, None, Precedence::None),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::Parser;
+    use crate::error::ErrorResult;
+    use crate::value::Value;
+    use crate::vm::VM;
+
+    fn print(_: String) -> ErrorResult<()> {
+        Ok(())
+    }
+
+    // Runs `source` with natives a(), b(), c() and tick() that log their
+    // calls, and f() that logs its arguments, returning the log.
+    fn call_log(source: &str) -> (VM, Vec<String>) {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new(print);
+
+        for (name, value) in [("a", 1.0), ("b", 2.0), ("c", 3.0)] {
+            let log = log.clone();
+            vm.set_global(name, Value::from_native(name, move |_| {
+                log.borrow_mut().push(name.to_string());
+                Ok(Value::Number(value))
+            }));
+        }
+
+        let ticks = log.clone();
+        vm.set_global("tick", Value::from_native("tick", move |_| {
+            let mut ticks = ticks.borrow_mut();
+            ticks.push("tick".to_string());
+            Ok(Value::Number(ticks.len() as f64))
+        }));
+
+        let calls = log.clone();
+        vm.set_global("f", Value::from_native("f", move |args| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            calls.borrow_mut().push(format!("f({})", args.join(", ")));
+            Ok(Value::Nil)
+        }));
+
+        let chunk = Parser::parse_str(source).unwrap();
+        vm.interpret(chunk).unwrap();
+
+        let log = log.borrow().clone();
+        (vm, log)
+    }
+
+    #[test]
+    fn call_arguments_are_evaluated_left_to_right() {
+        let (_, log) = call_log("f(tick(), tick(), tick());");
+        assert_eq!(log, ["tick", "tick", "tick", "f(1, 2, 3)"]);
+    }
+
+    #[test]
+    fn binary_operands_are_evaluated_left_to_right() {
+        let (vm, log) = call_log("var r = a() + b() * c();");
+        assert_eq!(log, ["a", "b", "c"]);
+        assert_eq!(vm.get_global("r"), Some(&Value::Number(7.0)));
+
+        let (_, log) = call_log("var r = a() * b() + c();");
+        assert_eq!(log, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn decimal_comma_is_reported() {
+        let errors = Parser::check("var x = 1,5;").unwrap_err();
+        assert_eq!(errors, ["[line 1] Error E002: Numbers use '.' for decimals; did you mean 1.5?"]);
+
+        let errors = Parser::check("print 2,25;").unwrap_err();
+        assert_eq!(errors, ["[line 1] Error E002: Numbers use '.' for decimals; did you mean 2.25?"]);
+
+        assert!(Parser::check("var x, y = 1,5; print x + y;").is_ok());
+    }
+
+    #[test]
+    fn value_lists_are_evaluated_left_to_right() {
+        let (_, log) = call_log("var x, y = a(), b(); x, y = c(), a();");
+        assert_eq!(log, ["a", "b", "c", "a"]);
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt::Write;
+
+use crate::chunk::{Chunk, DecodedInstruction, Instruction};
+
+pub fn disassemble_chunk(chunk: &Chunk) -> String {
+    let mut output = String::new();
+    let mut previous_line = None;
+
+    writeln!(output, "== {} ==", chunk.name()).unwrap();
+
+    for decoded in chunk.instructions() {
+        let same_line = previous_line == Some(decoded.line);
+        previous_line = Some(decoded.line);
+
+        let mut text = disassemble_instruction(&decoded, same_line);
+        if let Some(name) = local_slot(&decoded).and_then(|slot| chunk.local_name(slot, decoded.offset)) {
+            write!(text, " ; {}", name).unwrap();
+        }
+
+        writeln!(output, "{}", text).unwrap();
+    }
+
+    output
+}
+
+pub fn disassemble_instruction(decoded: &DecodedInstruction, same_line: bool) -> String {
+    let line = if same_line {
+        "   |".to_string()
+    } else {
+        format!("{:4}", decoded.line)
+    };
+
+    let operands = match &decoded.instruction {
+        Instruction::Constant { index, value } => format!("{:4} {}", index, value.repr()),
+        Instruction::DefineGlobal { index, name: value }
+        | Instruction::GetGlobal { index, name: value }
+        | Instruction::SetGlobal { index, name: value } => format!("{:4} '{}'", index, value),
+        Instruction::Invoke { index, name, arg_count } => format!("({} args) {:4} '{}'", arg_count, index, name),
+        Instruction::Call { arg_count } => format!("({} args)", arg_count),
+        Instruction::PopN { count } => format!("{:4}", count),
+        Instruction::SmallInt { value } => format!("{:4}", value),
+        Instruction::GetLocal { slot }
+        | Instruction::SetLocal { slot }
+        | Instruction::IterNext { slot } => format!("{:4}", slot),
+        Instruction::Jump { offset }
+        | Instruction::JumpIfFalse { offset }
+        | Instruction::JumpIfNil { offset }
+        | Instruction::Defer { offset } => {
+            format!("{:04} -> {:04}", decoded.offset, decoded.offset + 3 + *offset as usize)
+        },
+        Instruction::Loop { offset } => {
+            format!("{:04} -> {:04}", decoded.offset, (decoded.offset + 3).saturating_sub(*offset as usize))
+        },
+        Instruction::Invalid { byte } => format!("{:4}", byte),
+        _ => String::new()
+    };
+
+    format!("{:04} {} {:<16} {}", decoded.offset, line, decoded.instruction.name(), operands)
+        .trim_end()
+        .to_string()
+}
+
+fn local_slot(decoded: &DecodedInstruction) -> Option<u8> {
+    match decoded.instruction {
+        Instruction::GetLocal { slot } | Instruction::SetLocal { slot } | Instruction::IterNext { slot } => Some(slot),
+        _ => None
+    }
+}
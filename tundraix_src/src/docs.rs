@@ -0,0 +1,60 @@
+use crate::tokenizer::{Token, TokenType, Tokenizer};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DocComment {
+    pub name: String,
+    pub text: String,
+    // The line of the declaration the comment documents.
+    pub line: usize
+}
+
+// Collects `///` comments written directly above a `var` declaration.
+// Consecutive doc lines are joined with newlines; a blank line or any
+// other token between the comment and the declaration detaches it.
+pub fn doc_comments(source: &str) -> Vec<DocComment> {
+    let mut tokenizer = Tokenizer::new(source).with_comments(true);
+    let mut docs = Vec::new();
+    let mut pending: Vec<Token> = Vec::new();
+
+    loop {
+        let token = tokenizer.scan_token();
+
+        match token.ty {
+            TokenType::EndOfFile => break,
+            TokenType::Comment => {
+                let follows = pending.last().is_some_and(|last| last.line + 1 == token.line);
+                if !follows {
+                    pending.clear();
+                }
+
+                if token.text().starts_with("///") {
+                    pending.push(token);
+                } else {
+                    pending.clear();
+                }
+            },
+            TokenType::Var => {
+                let attached = pending.last().is_some_and(|last| last.line + 1 == token.line);
+                let name = tokenizer.scan_token();
+
+                if attached && name.ty == TokenType::Ident {
+                    let text = pending.iter()
+                        .map(|comment| comment.text()[3..].strip_prefix(' ').unwrap_or(&comment.text()[3..]))
+                        .collect::<Vec<_>>()
+                        .join("\n");
+
+                    docs.push(DocComment {
+                        name: name.text().to_string(),
+                        text,
+                        line: token.line
+                    });
+                }
+
+                pending.clear();
+            },
+            _ => pending.clear()
+        }
+    }
+
+    docs
+}
@@ -1,2 +1,145 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
 pub type Error = String;
-pub type ErrorResult<T> = Result<T, Error>;
\ No newline at end of file
+pub type ErrorResult<T> = Result<T, Error>;
+pub type RuntimeResult<T> = Result<T, RuntimeError>;
+pub type NativeResult<T> = Result<T, NativeError>;
+
+// What natives and methods fail with. The built-in ones give each error
+// its code with ErrorCode::error; plain messages, like those of the
+// host's own natives, convert into errors without one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NativeError {
+    pub code: Option<&'static str>,
+    pub message: Error
+}
+
+impl From<Error> for NativeError {
+    fn from(message: Error) -> Self {
+        Self {
+            code: None,
+            message
+        }
+    }
+}
+
+impl From<&str> for NativeError {
+    fn from(message: &str) -> Self {
+        Self::from(Error::from(message))
+    }
+}
+
+impl From<NativeError> for Error {
+    fn from(error: NativeError) -> Self {
+        error.message
+    }
+}
+
+impl Display for NativeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceFrame {
+    pub function: String,
+    // The line of the call that entered this frame, `None` for the
+    // top level script.
+    pub call_line: Option<usize>
+}
+
+impl TraceFrame {
+    pub fn new(function: &str, call_line: Option<usize>) -> Self {
+        Self {
+            function: function.to_string(),
+            call_line
+        }
+    }
+}
+
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self.call_line {
+            Some(line) => write!(f, "in {}() called from line {}", self.function, line),
+            None => write!(f, "at top level")
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub offset: usize,
+    pub name: String,
+    pub line: usize
+}
+
+impl HistoryEntry {
+    pub fn new(offset: usize, name: &str, line: usize) -> Self {
+        Self {
+            offset,
+            name: name.to_string(),
+            line
+        }
+    }
+}
+
+impl Display for HistoryEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{:04} {} (line {})", self.offset, self.name, self.line)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RuntimeError {
+    pub message: Error,
+    // The error's code from the codes registry, if it has one.
+    pub code: Option<&'static str>,
+    pub line: usize,
+    // Innermost frame first.
+    pub trace: Vec<TraceFrame>,
+    // The instructions executed before the error, oldest first. Only
+    // recorded when the VM's history is turned on.
+    pub history: Vec<HistoryEntry>
+}
+
+impl RuntimeError {
+    pub fn new(message: Error, line: usize, trace: Vec<TraceFrame>) -> Self {
+        Self {
+            code: None,
+            message,
+            line,
+            trace,
+            history: Vec::new()
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self.code {
+            Some(code) => write!(f, "[line {}] Error {}: {}", self.line, code, self.message)?,
+            None => write!(f, "[line {}] Error: {}", self.line, self.message)?
+        }
+
+        for frame in &self.trace {
+            write!(f, "\n  {}", frame)?;
+        }
+
+        if !self.history.is_empty() {
+            write!(f, "\n  recent instructions:")?;
+
+            for entry in &self.history {
+                write!(f, "\n    {}", entry)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(error: RuntimeError) -> Self {
+        error.to_string()
+    }
+}
@@ -0,0 +1,41 @@
+use std::process::{Command, Stdio};
+
+use crate::codes;
+use crate::error::NativeResult;
+
+// What the host's policy decides for one exec() call.
+pub enum ExecDecision {
+    Run,
+    // Fails the call with this reason.
+    Deny(String),
+    // Returns this instead of running anything, e.g. canned output for
+    // tests.
+    Reply(ExecOutput)
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct ExecOutput {
+    // None if the process was ended by a signal.
+    pub status: Option<i32>,
+    pub stdout: String,
+    pub stderr: String
+}
+
+// Called with the command and its arguments before each exec() call.
+pub type ExecPolicyFn = Box<dyn FnMut(&str, &[String]) -> ExecDecision>;
+
+// Runs the command directly, not through a shell, with no input, and
+// waits for it to finish. Output that isn't UTF-8 is converted lossily.
+pub(crate) fn run(command: &str, args: &[String]) -> NativeResult<ExecOutput> {
+    let output = Command::new(command)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|error| codes::COMMAND_FAILED.error(format!("exec() could not run '{}': {}", command, error)))?;
+
+    Ok(ExecOutput {
+        status: output.status.code(),
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned()
+    })
+}
@@ -0,0 +1,217 @@
+use crate::compiler::Parser;
+use crate::error::ErrorResult;
+use crate::tokenizer::{Token, TokenType, Tokenizer};
+
+const INDENT: &str = "    ";
+
+// Prints the source back from its tokens with one statement per line,
+// four space indentation per block and single spaces around operators.
+// Comments stay where they were, either after code on the same line or
+// on a line of their own, and single blank lines between statements are
+// kept. Formatting formatted source gives the same text back.
+//
+// Source that doesn't compile is not formatted; the compile errors are
+// returned instead so the caller can leave it as it is.
+pub fn format_source(source: &str) -> ErrorResult<String> {
+    Parser::check(source).map_err(|errors| errors.join("\n"))?;
+
+    let mut tokenizer = Tokenizer::new(source).with_comments(true);
+    let mut formatter = Formatter::default();
+
+    loop {
+        let token = tokenizer.scan_token();
+        if token.ty == TokenType::EndOfFile {
+            break;
+        }
+
+        formatter.token(token);
+    }
+
+    Ok(formatter.finish())
+}
+
+#[derive(Default)]
+struct Formatter<'src> {
+    out: String,
+    line: String,
+    // The indentation of the line being built, taken when its first
+    // token is added.
+    line_depth: usize,
+    depth: usize,
+    parens: usize,
+    newline_pending: bool,
+    // Set after `!` or a unary `-`, which stay attached to their operand.
+    unary: bool,
+    // `match` keywords whose `{` hasn't been seen yet, and for each open
+    // brace the paren depth inside it if it holds match arms. Arms go one
+    // per line and the closing brace doesn't end the line.
+    pending_matches: usize,
+    braces: Vec<Option<usize>>,
+    previous: Option<Token<'src>>
+}
+
+impl<'src> Formatter<'src> {
+    fn token(&mut self, token: Token<'src>) {
+        if token.ty == TokenType::Comment {
+            return self.comment(token)
+        }
+
+        if token.ty == TokenType::RBrace {
+            self.depth = self.depth.saturating_sub(1);
+            self.newline_pending = true;
+        }
+
+        let continues_block = token.ty == TokenType::Else
+            && self.previous.as_ref().is_some_and(|previous| previous.ty == TokenType::RBrace);
+
+        if self.newline_pending && !continues_block {
+            self.break_line(&token);
+        }
+        self.newline_pending = false;
+
+        let space = self.needs_space(&token);
+        self.push(&Self::text(&token), space);
+
+        self.unary = match token.ty {
+            TokenType::Bang => true,
+            TokenType::Minus => !self.previous.as_ref().is_some_and(|previous| Self::ends_operand(&previous.ty)),
+            _ => false
+        };
+
+        match token.ty {
+            TokenType::LParen => self.parens += 1,
+            TokenType::RParen => self.parens = self.parens.saturating_sub(1),
+            TokenType::Match => self.pending_matches += 1,
+            TokenType::LBrace => {
+                let arms = if self.pending_matches > 0 {
+                    self.pending_matches -= 1;
+                    Some(self.parens)
+                } else {
+                    None
+                };
+
+                self.braces.push(arms);
+                self.depth += 1;
+                self.newline_pending = true;
+            },
+            TokenType::RBrace => self.newline_pending = !matches!(self.braces.pop(), Some(Some(_))),
+            TokenType::Comma if self.braces.last() == Some(&Some(self.parens)) => self.newline_pending = true,
+            TokenType::Semicolon if self.parens == 0 => self.newline_pending = true,
+            _ => {}
+        }
+
+        self.previous = Some(token);
+    }
+
+    fn comment(&mut self, token: Token<'src>) {
+        let trailing = self.previous.as_ref().is_some_and(|previous| previous.line == token.line)
+            && !self.line.is_empty();
+
+        if !trailing {
+            self.break_line(&token);
+        }
+
+        self.push(token.text(), trailing);
+        self.newline_pending = true;
+        self.unary = false;
+        self.previous = Some(token);
+    }
+
+    fn push(&mut self, text: &str, space: bool) {
+        if self.line.is_empty() {
+            self.line_depth = self.depth;
+        } else if space {
+            self.line.push(' ');
+        }
+
+        self.line.push_str(text);
+    }
+
+    // Ends the current output line, keeping one blank line if the source
+    // had any between the previous token and this one.
+    fn break_line(&mut self, next: &Token) {
+        self.flush_line();
+
+        let previous = match &self.previous {
+            Some(previous) => previous,
+            None => return
+        };
+
+        let after_block_start = previous.ty == TokenType::LBrace;
+        let before_block_end = next.ty == TokenType::RBrace;
+
+        if Self::start_line(next) > previous.line + 1 && !after_block_start && !before_block_end {
+            self.out.push('\n');
+        }
+    }
+
+    fn flush_line(&mut self) {
+        if self.line.is_empty() {
+            return
+        }
+
+        for _ in 0..self.line_depth {
+            self.out.push_str(INDENT);
+        }
+
+        self.out.push_str(&self.line);
+        self.out.push('\n');
+        self.line.clear();
+    }
+
+    fn finish(mut self) -> String {
+        self.flush_line();
+        self.out
+    }
+
+    fn needs_space(&self, token: &Token) -> bool {
+        let previous = match &self.previous {
+            Some(previous) => previous,
+            None => return false
+        };
+
+        if self.unary {
+            return false
+        }
+
+        match token.ty {
+            TokenType::Semicolon | TokenType::Comma | TokenType::RParen | TokenType::LBracket
+                | TokenType::RBracket | TokenType::Dot | TokenType::QuestionDot | TokenType::DotDot => return false,
+            TokenType::LParen if Self::ends_value(&previous.ty) => return false,
+            _ => {}
+        }
+
+        !matches!(
+            previous.ty,
+            TokenType::LParen | TokenType::LBracket | TokenType::Dot | TokenType::QuestionDot | TokenType::DotDot
+        )
+    }
+
+    // A minus is unary unless it follows something that ends an operand.
+    fn ends_operand(ty: &TokenType) -> bool {
+        matches!(
+            ty,
+            TokenType::Ident | TokenType::Number | TokenType::String | TokenType::RParen
+                | TokenType::RBracket | TokenType::True | TokenType::False | TokenType::Nil | TokenType::This | TokenType::Super
+        )
+    }
+
+    fn ends_value(ty: &TokenType) -> bool {
+        matches!(ty, TokenType::Ident | TokenType::RParen | TokenType::String)
+    }
+
+    // Multi-line strings carry the line they end on.
+    fn start_line(token: &Token) -> usize {
+        match token.ty {
+            TokenType::String => token.line - token.text().matches('\n').count(),
+            _ => token.line
+        }
+    }
+
+    fn text(token: &Token) -> String {
+        match token.ty {
+            TokenType::String => format!("\"{}\"", token.text()),
+            _ => token.text().to_string()
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use crate::value::Value;
+
+// Suggestions are only looked for among names this close to the
+// misspelled one, and only in tables up to this size.
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+const MAX_SUGGESTION_CANDIDATES: usize = 4096;
+
+// A global's name resolved once with VM::intern, so the host can read
+// and write it later without hashing the name again. Only valid with the
+// VM that made it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Symbol {
+    pub(crate) vm: u32,
+    pub(crate) index: usize
+}
+
+// Globals live in slots numbered in the order their names were first
+// seen. Interning a name reserves its slot without defining the global.
+#[derive(Default)]
+pub(crate) struct Globals {
+    indices: HashMap<String, usize>,
+    names: Vec<String>,
+    values: Vec<Option<Value>>
+}
+
+impl Globals {
+    pub(crate) fn intern(&mut self, name: &str) -> usize {
+        if let Some(index) = self.indices.get(name) {
+            return *index
+        }
+
+        let index = self.names.len();
+        self.indices.insert(name.to_string(), index);
+        self.names.push(name.to_string());
+        self.values.push(None);
+        index
+    }
+
+    // Makes room for `total` globals in all.
+    pub(crate) fn reserve(&mut self, total: usize) {
+        let additional = total.saturating_sub(self.names.len());
+        self.indices.reserve(additional);
+        self.names.reserve(additional);
+        self.values.reserve(additional);
+    }
+
+    // Undefines every global, keeping the slots and their memory.
+    pub(crate) fn clear(&mut self) {
+        self.values.iter_mut().for_each(|value| *value = None);
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&Value> {
+        self.indices.get(name).and_then(|index| self.get_at(*index))
+    }
+
+    pub(crate) fn get_mut(&mut self, name: &str) -> Option<&mut Value> {
+        match self.indices.get(name) {
+            Some(index) => self.values[*index].as_mut(),
+            None => None
+        }
+    }
+
+    pub(crate) fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub(crate) fn insert(&mut self, name: &str, value: Value) {
+        let index = self.intern(name);
+        self.values[index] = Some(value);
+    }
+
+    // Undefines the global. Its slot stays reserved, so symbols for it
+    // remain valid.
+    pub(crate) fn remove(&mut self, name: &str) -> bool {
+        match self.indices.get(name) {
+            Some(index) => self.values[*index].take().is_some(),
+            None => false
+        }
+    }
+
+    pub(crate) fn get_at(&self, index: usize) -> Option<&Value> {
+        self.values.get(index).and_then(Option::as_ref)
+    }
+
+    pub(crate) fn set_at(&mut self, index: usize, value: Value) {
+        self.values[index] = Some(value);
+    }
+
+    // The defined globals, in slot order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.names.iter()
+            .zip(&self.values)
+            .filter_map(|(name, value)| value.as_ref().map(|value| (name.as_str(), value)))
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &Value> {
+        self.values.iter().flatten()
+    }
+
+    // The defined global whose name is closest to `name`, for "did you
+    // mean" hints. Ties go to the alphabetically first name.
+    pub(crate) fn closest(&self, name: &str) -> Option<&str> {
+        if self.names.len() > MAX_SUGGESTION_CANDIDATES {
+            return None
+        }
+
+        self.iter()
+            .map(|(candidate, _)| candidate)
+            .filter(|candidate| *candidate != name)
+            .filter_map(|candidate| {
+                let distance = edit_distance(name, candidate, MAX_SUGGESTION_DISTANCE)?;
+                Some((distance, candidate))
+            })
+            .min()
+            .map(|(_, candidate)| candidate)
+    }
+}
+
+// The optimal string alignment distance between `a` and `b`: insertions,
+// deletions, substitutions and swaps of neighbouring characters each
+// count one. None if it's more than `max`.
+fn edit_distance(a: &str, b: &str, max: usize) -> Option<usize> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    if a.len().abs_diff(b.len()) > max {
+        return None
+    }
+
+    // Three rows of the usual table: two back, one back and current.
+    let mut before: Vec<usize> = vec![0; b.len() + 1];
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current: Vec<usize> = vec![0; b.len() + 1];
+
+    for i in 1..=a.len() {
+        current[0] = i;
+
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+
+        std::mem::swap(&mut before, &mut previous);
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    let distance = previous[b.len()];
+    if distance <= max {
+        Some(distance)
+    } else {
+        None
+    }
+}
@@ -1,6 +1,20 @@
 pub mod error;
 pub mod tokenizer;
 pub mod chunk;
+pub mod codes;
 pub mod vm;
 pub mod compiler;
-pub mod value;
\ No newline at end of file
+pub mod value;
+pub mod disassembler;
+pub mod docs;
+pub mod exec;
+pub mod format;
+pub mod globals;
+pub mod methods;
+pub mod limits;
+pub mod natives;
+pub mod optimize;
+pub mod output;
+pub mod profiler;
+pub mod sandbox;
+pub mod session;
\ No newline at end of file
@@ -0,0 +1,52 @@
+use crate::value::MAX_STRING_LENGTH;
+
+// Hard limits of the compiler and the VM. The defaults for constants,
+// locals, arguments and jumps are also the most the bytecode can encode,
+// and strings can't get longer than MAX_STRING_LENGTH, so those can only
+// be tightened. Nesting and stack size can go either way.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Limits {
+    // Constants per chunk.
+    pub constants: usize,
+    // Locals alive at the same time, hidden loop locals included.
+    pub locals: usize,
+    pub arguments: usize,
+    // The longest forward jump or backward loop, in bytes.
+    pub jump: usize,
+    // How deeply statements and expressions may nest.
+    pub nesting: usize,
+    // Values on the VM's stack.
+    pub stack: usize,
+    // The longest string a script can build, in bytes.
+    pub string_length: usize
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            constants: 256,
+            locals: 256,
+            arguments: 255,
+            jump: u16::MAX as usize,
+            nesting: 200,
+            stack: 256,
+            string_length: MAX_STRING_LENGTH
+        }
+    }
+}
+
+impl Limits {
+    pub(crate) fn clamped(self) -> Self {
+        let max = Self::default();
+
+        Self {
+            constants: self.constants.min(max.constants),
+            locals: self.locals.min(max.locals),
+            arguments: self.arguments.min(max.arguments),
+            jump: self.jump.min(max.jump),
+            nesting: self.nesting,
+            stack: self.stack,
+            string_length: self.string_length.min(max.string_length)
+        }
+    }
+}
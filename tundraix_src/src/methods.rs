@@ -0,0 +1,83 @@
+use crate::codes;
+use crate::error::NativeResult;
+use crate::value::Value;
+
+// Built-in methods receive the receiver followed by the call's arguments.
+pub type MethodFn = fn(&[Value]) -> NativeResult<Value>;
+
+pub fn find_method(receiver: &Value, name: &str) -> Option<MethodFn> {
+    match receiver {
+        Value::String(_) => string_method(name),
+        Value::Number(_) => number_method(name),
+        _ => None
+    }
+}
+
+fn string_method(name: &str) -> Option<MethodFn> {
+    let method: MethodFn = match name {
+        "len" => string_len,
+        "upper" => string_upper,
+        "lower" => string_lower,
+        _ => return None
+    };
+
+    Some(method)
+}
+
+fn number_method(name: &str) -> Option<MethodFn> {
+    let method: MethodFn = match name {
+        "abs" => number_abs,
+        "floor" => number_floor,
+        "ceil" => number_ceil,
+        "round" => number_round,
+        _ => return None
+    };
+
+    Some(method)
+}
+
+fn expect_args(name: &str, args: &[Value], count: usize) -> NativeResult<()> {
+    // The receiver isn't counted as an argument.
+    let given = args.len() - 1;
+
+    if given != count {
+        return Err(codes::ARGUMENT_COUNT.error(format!("{}() expects {} arguments but got {}.", name, count, given)));
+    }
+
+    Ok(())
+}
+
+fn string_len(args: &[Value]) -> NativeResult<Value> {
+    expect_args("len", args, 0)?;
+    Ok(Value::Number(args[0].as_string().chars().count() as f64))
+}
+
+fn string_upper(args: &[Value]) -> NativeResult<Value> {
+    expect_args("upper", args, 0)?;
+    Ok(Value::String(args[0].as_string().to_uppercase().into()))
+}
+
+fn string_lower(args: &[Value]) -> NativeResult<Value> {
+    expect_args("lower", args, 0)?;
+    Ok(Value::String(args[0].as_string().to_lowercase().into()))
+}
+
+fn number_abs(args: &[Value]) -> NativeResult<Value> {
+    expect_args("abs", args, 0)?;
+    Ok(Value::Number(args[0].as_number().abs()))
+}
+
+fn number_floor(args: &[Value]) -> NativeResult<Value> {
+    expect_args("floor", args, 0)?;
+    Ok(Value::Number(args[0].as_number().floor()))
+}
+
+fn number_ceil(args: &[Value]) -> NativeResult<Value> {
+    expect_args("ceil", args, 0)?;
+    Ok(Value::Number(args[0].as_number().ceil()))
+}
+
+fn number_round(args: &[Value]) -> NativeResult<Value> {
+    expect_args("round", args, 0)?;
+    Ok(Value::Number(args[0].as_number().round()))
+}
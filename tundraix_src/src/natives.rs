@@ -0,0 +1,676 @@
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::codes;
+use crate::error::NativeResult;
+use crate::output::Level;
+use crate::value::Value;
+use crate::vm::VM;
+
+pub type NativeFn = fn(&mut VM, &[Value]) -> NativeResult<Value>;
+
+pub const STANDARD_NATIVES: &[(&str, NativeFn)] = &[
+    ("len", len),
+    ("bool", bool),
+    ("num", num),
+    ("upper", upper),
+    ("lower", lower),
+    ("trim", trim),
+    ("trim_start", trim_start),
+    ("trim_end", trim_end),
+    ("starts_with", starts_with),
+    ("ends_with", ends_with),
+    ("replace", replace),
+    ("repeat", repeat),
+    ("format", format),
+    ("arg_count", arg_count),
+    ("arg", arg),
+    ("log_debug", log_debug),
+    ("log_info", log_info),
+    ("log_warn", log_warn),
+    ("log_error", log_error),
+    ("wait", wait),
+    ("version", version),
+    ("has_native", has_native),
+    ("has_feature", has_feature),
+    ("expect_eq", expect_eq),
+    ("expect_error", expect_error)
+];
+
+// Only registered by VM::allow_fs.
+pub const FS_NATIVES: &[(&str, NativeFn)] = &[
+    ("read_file", read_file),
+    ("write_file", write_file)
+];
+
+// Only registered by VM::allow_env.
+pub const ENV_NATIVES: &[(&str, NativeFn)] = &[
+    ("env", env)
+];
+
+// Only registered by VM::allow_exec.
+pub const EXEC_NATIVES: &[(&str, NativeFn)] = &[
+    ("exec", exec),
+    ("exec_status", exec_status),
+    ("exec_stderr", exec_stderr)
+];
+
+// Optional features and the natives that provide them. A VM has a
+// feature when all of its natives are registered.
+pub const FEATURES: &[(&str, &[(&str, NativeFn)])] = &[
+    ("fs", FS_NATIVES),
+    ("env", ENV_NATIVES),
+    ("exec", EXEC_NATIVES)
+];
+
+pub fn expect_arity(name: &str, args: &[Value], count: usize) -> NativeResult<()> {
+    if args.len() != count {
+        let plural = if count == 1 { "" } else { "s" };
+        return Err(codes::ARGUMENT_COUNT.error(format!("{}() expects {} argument{} but got {}.", name, count, plural, args.len())));
+    }
+
+    Ok(())
+}
+
+pub fn expect_string<'a>(name: &str, args: &'a [Value], idx: usize) -> NativeResult<&'a str> {
+    match &args[idx] {
+        Value::String(string) => Ok(string),
+        other => Err(codes::INVALID_ARGUMENT.error(format!("{}() expects a string as argument {} but got {}.", name, idx + 1, other.type_name())))
+    }
+}
+
+pub fn expect_number(name: &str, args: &[Value], idx: usize) -> NativeResult<f64> {
+    match &args[idx] {
+        Value::Number(number) => Ok(*number),
+        other => Err(codes::INVALID_ARGUMENT.error(format!("{}() expects a number as argument {} but got {}.", name, idx + 1, other.type_name())))
+    }
+}
+
+fn len(_: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("len", args, 1)?;
+    let string = expect_string("len", args, 0)?;
+    Ok(Value::Number(string.chars().count() as f64))
+}
+
+// The value's truthiness as a bool; see Value::is_truthy.
+fn bool(_: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("bool", args, 1)?;
+    Ok(Value::Bool(args[0].is_truthy()))
+}
+
+// Parses a string written like a number literal, with an optional sign
+// and surrounding whitespace. Only '.' is a decimal point, so localized
+// input such as "3,14" gives nil rather than 3 or 314, as does anything
+// else that isn't a finite number. Numbers are returned as they are.
+fn num(_: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("num", args, 1)?;
+
+    if let Value::Number(number) = args[0] {
+        return Ok(Value::Number(number))
+    }
+
+    let text = expect_string("num", args, 0)?.trim();
+    let digits = text.strip_prefix(['-', '+']).unwrap_or(text);
+
+    let all_digits = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+    let is_literal = match digits.split_once('.') {
+        Some((whole, fraction)) => all_digits(whole) && all_digits(fraction),
+        None => all_digits(digits)
+    };
+
+    match text.parse::<f64>() {
+        Ok(number) if is_literal && number.is_finite() => Ok(Value::Number(number)),
+        _ => Ok(Value::Nil)
+    }
+}
+
+fn upper(_: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("upper", args, 1)?;
+    Ok(Value::String(expect_string("upper", args, 0)?.to_uppercase().into()))
+}
+
+fn lower(_: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("lower", args, 1)?;
+    Ok(Value::String(expect_string("lower", args, 0)?.to_lowercase().into()))
+}
+
+fn trim(_: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("trim", args, 1)?;
+    Ok(Value::String(expect_string("trim", args, 0)?.trim().to_string().into()))
+}
+
+fn trim_start(_: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("trim_start", args, 1)?;
+    Ok(Value::String(expect_string("trim_start", args, 0)?.trim_start().to_string().into()))
+}
+
+fn trim_end(_: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("trim_end", args, 1)?;
+    Ok(Value::String(expect_string("trim_end", args, 0)?.trim_end().to_string().into()))
+}
+
+fn starts_with(_: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("starts_with", args, 2)?;
+    let string = expect_string("starts_with", args, 0)?;
+    let prefix = expect_string("starts_with", args, 1)?;
+    Ok(Value::Bool(string.starts_with(prefix)))
+}
+
+fn ends_with(_: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("ends_with", args, 2)?;
+    let string = expect_string("ends_with", args, 0)?;
+    let suffix = expect_string("ends_with", args, 1)?;
+    Ok(Value::Bool(string.ends_with(suffix)))
+}
+
+fn replace(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("replace", args, 3)?;
+    let string = expect_string("replace", args, 0)?;
+    let from = expect_string("replace", args, 1)?;
+    let to = expect_string("replace", args, 2)?;
+
+    if from.is_empty() {
+        return Err(codes::INVALID_ARGUMENT.error("replace() can't replace an empty string."));
+    }
+
+    let count = string.matches(from).count();
+    if string.len() - count * from.len() + count * to.len() > vm.limits().string_length {
+        return Err(codes::RUNTIME_LIMIT.error("replace() would create a string that is too long."));
+    }
+
+    Ok(Value::String(string.replace(from, to).into()))
+}
+
+fn repeat(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("repeat", args, 2)?;
+    let string = expect_string("repeat", args, 0)?;
+    let count = expect_number("repeat", args, 1)?;
+
+    if count < 0.0 || count.fract() != 0.0 {
+        return Err(codes::INVALID_ARGUMENT.error("repeat() expects a non-negative whole number of repetitions."));
+    }
+
+    if string.len() as f64 * count > vm.limits().string_length as f64 {
+        return Err(codes::RUNTIME_LIMIT.error("repeat() would create a string that is too long."));
+    }
+
+    Ok(Value::String(string.repeat(count as usize).into()))
+}
+
+fn arg_count(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("arg_count", args, 0)?;
+    Ok(Value::Number(vm.args().len() as f64))
+}
+
+// Arguments are returned as strings, nil past the last one.
+fn arg(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("arg", args, 1)?;
+    let index = expect_number("arg", args, 0)?;
+
+    if index < 0.0 || index.fract() != 0.0 {
+        return Err(codes::INVALID_ARGUMENT.error("arg() expects a non-negative whole number."));
+    }
+
+    match vm.args().get(index as usize) {
+        Some(arg) => Ok(Value::String(arg.clone().into())),
+        None => Ok(Value::Nil)
+    }
+}
+
+// Suspends the script for the given number of seconds; see VM::suspend.
+fn wait(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("wait", args, 1)?;
+    let seconds = expect_number("wait", args, 0)?;
+
+    let duration = Duration::try_from_secs_f64(seconds)
+        .map_err(|_| codes::INVALID_ARGUMENT.error(format!("wait() expects a non-negative number of seconds but got {}.", seconds)))?;
+
+    vm.suspend(duration);
+    Ok(Value::Nil)
+}
+
+fn version(_: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("version", args, 0)?;
+    Ok(Value::String(env!("CARGO_PKG_VERSION").to_string().into()))
+}
+
+fn has_native(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("has_native", args, 1)?;
+    let name = expect_string("has_native", args, 0)?;
+    Ok(Value::Bool(vm.has_native(name)))
+}
+
+fn has_feature(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("has_feature", args, 1)?;
+    let name = expect_string("has_feature", args, 0)?;
+    Ok(Value::Bool(vm.features().contains(&name)))
+}
+
+// The log natives take one value of any type and stringify it the same
+// way print does.
+fn log(vm: &mut VM, name: &str, level: Level, args: &[Value]) -> NativeResult<Value> {
+    expect_arity(name, args, 1)?;
+    vm.log(level, &args[0].to_string());
+    Ok(Value::Nil)
+}
+
+fn log_debug(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    log(vm, "log_debug", Level::Debug, args)
+}
+
+fn log_info(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    log(vm, "log_info", Level::Info, args)
+}
+
+fn log_warn(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    log(vm, "log_warn", Level::Warn, args)
+}
+
+fn log_error(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    log(vm, "log_error", Level::Error, args)
+}
+
+// Resolves a script supplied path against the file root. Symlinks and
+// `..` are resolved before checking, so nothing outside the root can be
+// reached. Files that don't exist yet are checked through their parent,
+// and a dangling symlink is refused since writing would follow it.
+fn resolve_path(vm: &VM, name: &str, path: &str) -> NativeResult<PathBuf> {
+    let root = vm.fs_root().ok_or_else(|| codes::ACCESS_DENIED.error(format!("{}() is not allowed.", name)))?;
+    let joined = root.join(path);
+
+    let resolved = match joined.canonicalize() {
+        Ok(resolved) => resolved,
+        Err(_) => {
+            if joined.symlink_metadata().is_ok() {
+                return Err(codes::ACCESS_DENIED.error(format!("{}() can't follow the broken link '{}'.", name, path)));
+            }
+
+            let file_name = joined.file_name()
+                .ok_or_else(|| codes::ACCESS_DENIED.error(format!("{}() got an invalid path '{}'.", name, path)))?;
+            let parent = joined.parent()
+                .and_then(|parent| parent.canonicalize().ok())
+                .ok_or_else(|| codes::ACCESS_DENIED.error(format!("{}() could not find the directory of '{}'.", name, path)))?;
+
+            parent.join(file_name)
+        }
+    };
+
+    if !resolved.starts_with(root) {
+        return Err(codes::ACCESS_DENIED.error(format!("{}() can't access '{}' outside of the file root.", name, path)));
+    }
+
+    Ok(resolved)
+}
+
+fn read_file(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("read_file", args, 1)?;
+    let path = expect_string("read_file", args, 0)?;
+    let resolved = resolve_path(vm, "read_file", path)?;
+
+    let read_error = |error: std::io::Error| codes::ACCESS_DENIED.error(format!("read_file() could not read '{}': {}", path, error));
+
+    // Reads one byte past the limit, so a large file is never read whole.
+    let limit = vm.limits().string_length;
+    let mut text = String::new();
+    fs::File::open(resolved)
+        .and_then(|file| file.take(limit as u64 + 1).read_to_string(&mut text))
+        .map_err(read_error)?;
+
+    if text.len() > limit {
+        return Err(codes::RUNTIME_LIMIT.error(format!("read_file() can't read '{}' because it is too large.", path)));
+    }
+
+    Ok(Value::String(text.into()))
+}
+
+fn write_file(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("write_file", args, 2)?;
+    let path = expect_string("write_file", args, 0)?;
+    let text = expect_string("write_file", args, 1)?;
+    let resolved = resolve_path(vm, "write_file", path)?;
+
+    fs::write(&resolved, text)
+        .map_err(|error| codes::ACCESS_DENIED.error(format!("write_file() could not write '{}': {}", path, error)))?;
+
+    // The path may have been replaced by a link since it was checked.
+    let inside = vm.fs_root()
+        .is_some_and(|root| resolved.canonicalize().is_ok_and(|written| written.starts_with(root)));
+    if !inside {
+        return Err(codes::ACCESS_DENIED.error(format!("write_file() wrote '{}' outside of the file root.", path)));
+    }
+
+    Ok(Value::Nil)
+}
+
+// Returns nil for allowed variables that aren't set.
+fn env(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("env", args, 1)?;
+    let name = expect_string("env", args, 0)?;
+
+    if !vm.env_allowed(name) {
+        return Err(codes::ACCESS_DENIED.error(format!("env() is not allowed to read '{}'.", name)));
+    }
+
+    match std::env::var(name) {
+        Ok(value) => Ok(Value::String(value.into())),
+        Err(_) => Ok(Value::Nil)
+    }
+}
+
+// `exec(command, args...)` returns the command's standard output without
+// its final line break, whatever its exit status. exec_status() and
+// exec_stderr() read the rest of the last call's result, which is
+// cleared first so a failed call never leaves an older one behind.
+fn exec(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    vm.set_last_exec(None);
+
+    if args.is_empty() {
+        return Err(codes::ARGUMENT_COUNT.error("exec() expects at least 1 argument but got 0."));
+    }
+
+    let command = expect_string("exec", args, 0)?;
+    let command_args = (1..args.len())
+        .map(|idx| expect_string("exec", args, idx).map(str::to_string))
+        .collect::<NativeResult<Vec<_>>>()?;
+
+    let output = vm.exec(command, &command_args)?;
+    let stdout = (output.stdout.len() <= vm.limits().string_length)
+        .then(|| trim_line_break(&output.stdout).to_string());
+    vm.set_last_exec(Some(output));
+
+    let stdout = stdout.ok_or_else(|| codes::COMMAND_FAILED.error(format!("exec() '{}' printed too much output.", command)))?;
+    Ok(Value::String(stdout.into()))
+}
+
+// The exit status of the last exec() call, or nil if there was none or
+// the command was ended by a signal.
+fn exec_status(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("exec_status", args, 0)?;
+
+    Ok(vm.last_exec()
+        .and_then(|output| output.status)
+        .map_or(Value::Nil, |status| Value::Number(status as f64)))
+}
+
+// The standard error of the last exec() call without its final line
+// break, or nil if there was none.
+fn exec_stderr(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("exec_stderr", args, 0)?;
+
+    let stderr = match vm.last_exec() {
+        Some(output) => trim_line_break(&output.stderr).to_string(),
+        None => return Ok(Value::Nil)
+    };
+
+    if stderr.len() > vm.limits().string_length {
+        return Err(codes::COMMAND_FAILED.error("exec_stderr() can't return more output than a string can hold."));
+    }
+
+    Ok(Value::String(stderr.into()))
+}
+
+fn trim_line_break(text: &str) -> &str {
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    text.strip_suffix('\r').unwrap_or(text)
+}
+
+struct FormatSpec {
+    align: Option<char>,
+    zero_pad: bool,
+    width: usize,
+    precision: Option<usize>,
+    kind: char
+}
+
+// Parses `[<|>][0][width][.precision](d|f|s)`, e.g. "0.2f", "05d" or "<10s".
+// Numbers are right aligned and strings left aligned unless `<` or `>`
+// says otherwise.
+fn parse_format_spec(spec: &str) -> NativeResult<FormatSpec> {
+    let invalid = || codes::INVALID_FORMAT.error(format!("format() got an invalid spec '{}'.", spec));
+    let mut chars = spec.chars().peekable();
+
+    let align = chars.next_if(|c| *c == '<' || *c == '>');
+
+    let zero_pad = chars.next_if_eq(&'0').is_some();
+
+    let mut width = String::new();
+    while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+        width.push(*c);
+        chars.next();
+    }
+
+    let mut precision = None;
+    if chars.next_if_eq(&'.').is_some() {
+        let mut digits = String::new();
+        while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+            digits.push(*c);
+            chars.next();
+        }
+
+        precision = Some(digits.parse().map_err(|_| invalid())?);
+    }
+
+    let kind = chars.next().ok_or_else(invalid)?;
+    if chars.next().is_some() {
+        return Err(invalid());
+    }
+
+    if !['d', 'f', 's'].contains(&kind) {
+        return Err(codes::INVALID_FORMAT.error(format!("format() doesn't support the '{}' specifier.", kind)));
+    }
+
+    Ok(FormatSpec {
+        align,
+        zero_pad,
+        width: if width.is_empty() { 0 } else { width.parse().map_err(|_| invalid())? },
+        precision,
+        kind
+    })
+}
+
+// Fixed-point output rounds the exact binary value of the number and
+// breaks exact ties to even, so format(0.125, ".2f") is "0.12" while
+// format(0.375, ".2f") is "0.38". The output never depends on locale.
+fn format(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("format", args, 2)?;
+    let spec = parse_format_spec(expect_string("format", args, 1)?)?;
+
+    let limit = vm.limits().string_length;
+    if spec.width > limit || spec.precision.is_some_and(|precision| precision > limit) {
+        return Err(codes::RUNTIME_LIMIT.error("format() would create a string that is too long."));
+    }
+
+    let text = match spec.kind {
+        'd' => {
+            let number = expect_number("format", args, 0)?;
+
+            if number.fract() != 0.0 || !number.is_finite() {
+                return Err(codes::INVALID_FORMAT.error("format() spec 'd' expects a whole number."));
+            }
+
+            if spec.precision.is_some() {
+                return Err(codes::INVALID_FORMAT.error("format() spec 'd' doesn't take a precision."));
+            }
+
+            std::format!("{:.0}", number)
+        },
+        'f' => {
+            let number = expect_number("format", args, 0)?;
+            std::format!("{:.*}", spec.precision.unwrap_or(6), number)
+        },
+        _ => {
+            if spec.zero_pad {
+                return Err(codes::INVALID_FORMAT.error("format() spec 's' can't be zero padded."));
+            }
+
+            let text = args[0].to_string();
+            match spec.precision {
+                Some(precision) => text.chars().take(precision).collect(),
+                None => text
+            }
+        }
+    };
+
+    let padding = spec.width.saturating_sub(text.chars().count());
+    if padding == 0 {
+        return Ok(Value::String(text.into()))
+    }
+
+    let padded = if spec.zero_pad {
+        match text.strip_prefix('-') {
+            Some(digits) => std::format!("-{}{}", "0".repeat(padding), digits),
+            None => std::format!("{}{}", "0".repeat(padding), text)
+        }
+    } else if spec.align == Some('<') || (spec.align.is_none() && spec.kind == 's') {
+        std::format!("{}{}", text, " ".repeat(padding))
+    } else {
+        std::format!("{}{}", " ".repeat(padding), text)
+    };
+
+    Ok(Value::String(padded.into()))
+}
+
+// Records whether `actual` equals `expected` on the VM instead of
+// failing the script, so one run reports every failed check.
+fn expect_eq(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    expect_arity("expect_eq", args, 3)?;
+
+    let failure = if args[0] == args[1] {
+        None
+    } else {
+        Some(format!("expected {} but got {}", args[1].repr(), args[0].repr()))
+    };
+
+    vm.record_test(args[2].to_string(), failure);
+    Ok(Value::Nil)
+}
+
+// Calls `fn` with any further arguments and records whether it failed
+// with an error containing `substring`. The error doesn't stop the
+// script.
+fn expect_error(vm: &mut VM, args: &[Value]) -> NativeResult<Value> {
+    if args.len() < 2 {
+        return Err(codes::ARGUMENT_COUNT.error(format!("expect_error() expects at least 2 arguments but got {}.", args.len())));
+    }
+
+    let substring = expect_string("expect_error", args, 1)?.to_string();
+    if !matches!(args[0], Value::Native(_) | Value::Closure(_)) {
+        return Err(codes::INVALID_ARGUMENT.error(format!("expect_error() expects a function as argument 1 but got {}.", args[0].type_name())));
+    }
+
+    let failure = match vm.call_value(&args[0], &args[2..]) {
+        Ok(value) => Some(format!("expected an error but got {}", value.repr())),
+        Err(error) if error.message.contains(&substring) => None,
+        Err(error) => Some(format!("expected an error containing {} but got {}", args[1].repr(), Value::String(error.message.into()).repr()))
+    };
+
+    vm.record_test(format!("error containing {}", args[1].repr()), failure);
+    Ok(Value::Nil)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::compiler::Parser;
+    use crate::error::{ErrorResult, RuntimeResult};
+    use crate::exec::{ExecDecision, ExecOutput};
+    use crate::value::Value;
+    use crate::vm::VM;
+
+    fn print(_: String) -> ErrorResult<()> {
+        Ok(())
+    }
+
+    // A fresh directory holding a `root` the script may use and an
+    // `outside` it must not reach.
+    fn fs_dirs(test: &str) -> (PathBuf, PathBuf) {
+        let base = std::env::temp_dir().join(format!("tundraix-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+
+        let (root, outside) = (base.join("root"), base.join("outside"));
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret.txt"), "secret").unwrap();
+        (root, outside)
+    }
+
+    fn run(root: &Path, source: &str) -> RuntimeResult<()> {
+        let mut vm = VM::new(print);
+        vm.allow_fs(root).unwrap();
+        vm.interpret(Parser::parse_str(source).unwrap())
+    }
+
+    #[test]
+    fn parent_paths_cant_leave_the_root() {
+        let (root, outside) = fs_dirs("parent");
+
+        let error = run(&root, "read_file(\"../outside/secret.txt\");").unwrap_err();
+        assert_eq!(error.code, Some("E024"));
+
+        let error = run(&root, "write_file(\"../outside/new.txt\", \"x\");").unwrap_err();
+        assert_eq!(error.code, Some("E024"));
+        assert!(!outside.join("new.txt").exists());
+
+        run(&root, "write_file(\"inside.txt\", \"x\"); read_file(\"inside.txt\");").unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_cant_leave_the_root() {
+        let (root, outside) = fs_dirs("symlink");
+        std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("secret.txt")).unwrap();
+        std::os::unix::fs::symlink(outside.join("pwned.txt"), root.join("link.txt")).unwrap();
+
+        let error = run(&root, "read_file(\"secret.txt\");").unwrap_err();
+        assert_eq!(error.code, Some("E024"));
+
+        let error = run(&root, "write_file(\"link.txt\", \"x\");").unwrap_err();
+        assert_eq!(error.code, Some("E024"));
+        assert!(!outside.join("pwned.txt").exists());
+    }
+
+    // Answers "ok" and "fail" with canned output and denies the rest.
+    fn exec_vm() -> VM {
+        let mut vm = VM::new(print);
+        vm.allow_exec(|command, _| match command {
+            "ok" => ExecDecision::Reply(ExecOutput {
+                status: Some(0),
+                stdout: "out\n".to_string(),
+                stderr: String::new()
+            }),
+            "fail" => ExecDecision::Reply(ExecOutput {
+                status: Some(3),
+                stdout: String::new(),
+                stderr: "bad\n".to_string()
+            }),
+            _ => ExecDecision::Deny("not on the list".to_string())
+        });
+        vm
+    }
+
+    #[test]
+    fn exec_reports_the_last_result() {
+        let mut vm = exec_vm();
+        let source = "var out = exec(\"ok\"); exec(\"fail\"); var status = exec_status(); var stderr = exec_stderr();";
+        vm.interpret(Parser::parse_str(source).unwrap()).unwrap();
+
+        assert_eq!(vm.get_global("out"), Some(&Value::String("out".to_string().into())));
+        assert_eq!(vm.get_global("status"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.get_global("stderr"), Some(&Value::String("bad".to_string().into())));
+    }
+
+    #[test]
+    fn denied_exec_clears_the_last_result() {
+        let mut vm = exec_vm();
+        let source = "exec(\"fail\"); expect_error(exec, \"not on the list\", \"rm\"); var status = exec_status(); var stderr = exec_stderr();";
+        vm.interpret(Parser::parse_str(source).unwrap()).unwrap();
+
+        assert!(vm.test_results().len() == 1 && vm.test_results()[0].passed());
+        assert_eq!(vm.get_global("status"), Some(&Value::Nil));
+        assert_eq!(vm.get_global("stderr"), Some(&Value::Nil));
+    }
+}
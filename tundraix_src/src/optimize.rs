@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use crate::chunk::{Byte, Chunk, DecodedInstruction, Instruction, LocalInfo, OpCode};
+
+// Reads of globals that a loop never changes are hoisted out of it: each
+// such global is read once before the loop into a hidden stack slot, and
+// the reads inside become GET_LOCALs of that slot. Returns the number of
+// globals hoisted.
+//
+// A loop is left alone unless all of these hold:
+// - it has no calls, nested loops or returns
+// - no jump enters it from outside or leaves it other than to its exit
+// - its exit is followed by the POP or POP_N that clears the loop's own
+//   values, so the hidden slots can be popped right after it
+// A global is only hoisted if the loop never assigns or declares it and
+// an unconditional `var` before the loop declares it, so reading it
+// early can't fail where the original read wouldn't have.
+//
+// The hoisted value is what the global held when the loop started. A
+// host that changes the global between run_for calls while the loop is
+// still running won't be seen until the loop ends.
+pub fn hoist_loop_globals(chunk: &mut Chunk) -> usize {
+    let mut hoisted = 0;
+
+    // Each rewrite moves the code after it, so look again from the start.
+    while let Some((rewritten, count)) = hoist_next_loop(chunk) {
+        *chunk = rewritten;
+        hoisted += count;
+    }
+
+    hoisted
+}
+
+struct Loop {
+    start: usize,
+    // The instruction after the loop's own LOOP, where its exit lands.
+    exit: usize,
+    // The instruction after the POP or POP_N at the exit.
+    after: usize,
+    depth: usize
+}
+
+fn hoist_next_loop(chunk: &Chunk) -> Option<(Chunk, usize)> {
+    let instructions: Vec<DecodedInstruction> = chunk.instructions().collect();
+    let depths = chunk.stack_depths().ok()?;
+
+    instructions.iter()
+        .filter_map(|decoded| match decoded.instruction {
+            Instruction::Loop { offset } => Some((decoded.offset + 3 - offset as usize, decoded.offset + 3)),
+            _ => None
+        })
+        .find_map(|(start, exit)| {
+            let found = find_loop(&instructions, &depths, start, exit)?;
+            let names = invariant_globals(&instructions, &found);
+            if names.is_empty() {
+                return None
+            }
+
+            let rewritten = rewrite(chunk, &instructions, &found, &names)?;
+            Some((rewritten, names.len()))
+        })
+}
+
+fn find_loop(instructions: &[DecodedInstruction], depths: &[Option<usize>], start: usize, exit: usize) -> Option<Loop> {
+    let depth = (*depths.get(start)?)?;
+    let inside = |offset: usize| offset >= start && offset < exit;
+
+    for decoded in instructions {
+        if inside(decoded.offset) {
+            // The loop's own LOOP is the last instruction inside it.
+            match decoded.instruction {
+                Instruction::Call { .. } | Instruction::Return => return None,
+                Instruction::Loop { .. } if decoded.offset + 3 != exit => return None,
+                _ => {}
+            }
+        }
+
+        if let Some(target) = jump_target(decoded) {
+            let allowed = match inside(decoded.offset) {
+                true => inside(target) || target == exit,
+                false => !inside(target) && target != exit
+            };
+
+            if !allowed {
+                return None
+            }
+        }
+    }
+
+    let epilogue = instructions.iter().find(|decoded| decoded.offset == exit)?;
+    let popped = match epilogue.instruction {
+        Instruction::Pop => 1,
+        Instruction::PopN { count } => count as usize,
+        _ => return None
+    };
+
+    if (*depths.get(exit)?)? != depth + popped {
+        return None
+    }
+
+    Some(Loop {
+        start,
+        exit,
+        after: exit + epilogue.instruction.size(),
+        depth
+    })
+}
+
+// The constant index of each global the loop reads but never writes and
+// that is surely defined before the loop starts, in constant order.
+fn invariant_globals(instructions: &[DecodedInstruction], found: &Loop) -> Vec<u8> {
+    let mut read = BTreeMap::new();
+    let mut written = Vec::new();
+
+    for decoded in instructions.iter().filter(|decoded| decoded.offset >= found.start && decoded.offset < found.exit) {
+        match &decoded.instruction {
+            Instruction::GetGlobal { index, name } => {
+                read.insert(*index, name.to_string());
+            },
+            Instruction::SetGlobal { name, .. } | Instruction::DefineGlobal { name, .. } => written.push(name.to_string()),
+            _ => {}
+        }
+    }
+
+    read.into_iter()
+        .filter(|(_, name)| !written.contains(name))
+        .filter(|(_, name)| defined_before(instructions, name, found.start))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+// Whether a DEFINE_GLOBAL of `name` before `start` runs on every path
+// that reaches `start`, i.e. no jump or loop spans it.
+fn defined_before(instructions: &[DecodedInstruction], name: &str, start: usize) -> bool {
+    instructions.iter()
+        .filter(|decoded| decoded.offset < start)
+        .filter(|decoded| matches!(&decoded.instruction, Instruction::DefineGlobal { name: defined, .. } if defined.to_string() == name))
+        .any(|define| !instructions.iter().any(|decoded| match (jump_target(decoded), &decoded.instruction) {
+            (Some(target), Instruction::Loop { .. }) => target <= define.offset && define.offset < decoded.offset,
+            (Some(target), _) => decoded.offset < define.offset && define.offset < target,
+            (None, _) => false
+        }))
+}
+
+fn jump_target(decoded: &DecodedInstruction) -> Option<usize> {
+    match decoded.instruction {
+        Instruction::Jump { offset }
+        | Instruction::JumpIfFalse { offset }
+        | Instruction::JumpIfNil { offset }
+        | Instruction::Defer { offset } => Some(decoded.offset + 3 + offset as usize),
+        Instruction::Loop { offset } => Some(decoded.offset + 3 - offset as usize),
+        _ => None
+    }
+}
+
+fn rewrite(chunk: &Chunk, instructions: &[DecodedInstruction], found: &Loop, names: &[u8]) -> Option<Chunk> {
+    let count = names.len();
+    if found.depth + count > u8::MAX as usize {
+        return None
+    }
+
+    let start_line = chunk.get_byte(found.start).line;
+    let after_line = chunk.get_byte(found.exit).line;
+    let prologue = 2 * count;
+    let epilogue = if count == 1 { 1 } else { 2 };
+
+    let moved = |offset: usize| {
+        let mut moved = offset;
+        if offset >= found.start {
+            moved += prologue;
+        }
+        if offset >= found.after {
+            moved += epilogue;
+        }
+        moved
+    };
+
+    let mut code = Vec::with_capacity(chunk.len() + prologue + epilogue);
+
+    for decoded in instructions {
+        if decoded.offset == found.start {
+            for index in names {
+                code.push(Byte::new(OpCode::GetGlobal as u8, start_line));
+                code.push(Byte::new(*index, start_line));
+            }
+        }
+
+        if decoded.offset == found.after {
+            match count {
+                1 => code.push(Byte::new(OpCode::Pop as u8, after_line)),
+                _ => {
+                    code.push(Byte::new(OpCode::PopN as u8, after_line));
+                    code.push(Byte::new(count as u8, after_line));
+                }
+            }
+        }
+
+        let inside = decoded.offset >= found.start && decoded.offset < found.exit;
+        let mut bytes: Vec<u8> = (decoded.offset..decoded.offset + decoded.instruction.size())
+            .map(|offset| chunk.get_byte(offset).byte)
+            .collect();
+
+        match decoded.instruction {
+            Instruction::GetGlobal { index, .. } if inside && names.contains(&index) => {
+                let slot = found.depth + names.iter().position(|name| *name == index)?;
+                bytes = vec![OpCode::GetLocal as u8, slot as u8];
+            },
+            Instruction::GetLocal { slot } | Instruction::SetLocal { slot } | Instruction::IterNext { slot }
+                if inside && slot as usize >= found.depth =>
+            {
+                bytes[1] = u8::try_from(slot as usize + count).ok()?;
+            },
+            _ => {}
+        }
+
+        if let Some(target) = jump_target(decoded) {
+            let from = moved(decoded.offset) + 3;
+            let distance = match decoded.instruction {
+                Instruction::Loop { .. } => from - moved(target),
+                _ => moved(target) - from
+            };
+
+            let [high, low] = u16::try_from(distance).ok()?.to_be_bytes();
+            bytes[1] = high;
+            bytes[2] = low;
+        }
+
+        code.extend(bytes.into_iter().map(|byte| Byte::new(byte, decoded.line)));
+    }
+
+    // Locals declared inside the loop sit above the hidden slots.
+    let locals: Vec<LocalInfo> = chunk.local_infos().iter()
+        .map(|local| {
+            let mut local = local.clone();
+            if local.start >= found.start && local.start < found.exit && local.slot as usize >= found.depth {
+                local.slot = u8::try_from(local.slot as usize + count).ok()?;
+            }
+            local.start = moved(local.start);
+            local.end = moved(local.end);
+            Some(local)
+        })
+        .collect::<Option<_>>()?;
+
+    let mut rewritten = chunk.clone();
+    rewritten.truncate(0);
+    for byte in code {
+        rewritten.write_byte(byte);
+    }
+    *rewritten.local_infos_mut() = locals;
+
+    rewritten.verify_stack().ok()?;
+    Some(rewritten)
+}
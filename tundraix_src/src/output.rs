@@ -0,0 +1,54 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use crate::error::ErrorResult;
+
+pub type PrintFn = fn(String) -> ErrorResult<()>;
+pub type LogFn = Box<dyn FnMut(Level, &str)>;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let name = match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error"
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+// What the VM does when the output sink fails to write or flush. Abort
+// stops the script with a runtime error at the failing line; Ignore
+// drops the output and carries on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PrintErrorPolicy {
+    #[default]
+    Abort,
+    Ignore
+}
+
+// Where `print` sends its text. The VM flushes the sink whenever
+// interpret returns, including after a runtime error, so buffering sinks
+// never hold back output that was printed before the error.
+pub trait OutputSink {
+    fn write(&mut self, text: &str) -> ErrorResult<()>;
+
+    fn flush(&mut self) -> ErrorResult<()> {
+        Ok(())
+    }
+}
+
+impl OutputSink for PrintFn {
+    fn write(&mut self, text: &str) -> ErrorResult<()> {
+        self(text.to_string())
+    }
+}
@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use crate::limits::Limits;
+
+// Everything VM::sandboxed configures for running untrusted scripts.
+// Every preset guarantees:
+// - no file system, environment or command access, even if it was
+//   granted before, and none of the natives that provide it
+// - a runtime error after `instruction_limit` instructions
+// - a runtime error once a single load has run for `time_limit`, checked
+//   every 1024 instructions
+// - a runtime error when the stack would grow past `limits.stack` values
+// - a runtime error when a string would grow past `limits.string_length`
+//   bytes, whether built by `+` or by a native
+// Each setting can still be changed on the VM afterwards.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SandboxProfile {
+    pub instruction_limit: u64,
+    pub time_limit: Duration,
+    pub limits: Limits
+}
+
+impl SandboxProfile {
+    // For small snippets such as user supplied expressions or rules.
+    pub fn strict() -> Self {
+        Self {
+            instruction_limit: 1_000_000,
+            time_limit: Duration::from_millis(100),
+            limits: Limits {
+                nesting: 64,
+                stack: 64,
+                string_length: 64 * 1024,
+                ..Limits::default()
+            }
+        }
+    }
+
+    // For whole scripts that are expected to do real work.
+    pub fn relaxed() -> Self {
+        Self {
+            instruction_limit: 100_000_000,
+            time_limit: Duration::from_secs(5),
+            limits: Limits {
+                string_length: 1024 * 1024,
+                ..Limits::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SandboxProfile;
+    use crate::compiler::Parser;
+    use crate::error::{ErrorResult, RuntimeResult};
+    use crate::value::Value;
+    use crate::vm::VM;
+
+    fn print(_: String) -> ErrorResult<()> {
+        Ok(())
+    }
+
+    // A strict sandbox over a VM that was granted file and environment
+    // access first.
+    fn strict_vm() -> VM {
+        let mut vm = VM::new(print);
+        vm.allow_fs(std::env::temp_dir()).unwrap();
+        vm.allow_env(vec!["HOME".to_string()]);
+        vm.sandboxed(SandboxProfile::strict())
+    }
+
+    fn run(vm: &mut VM, source: &str) -> RuntimeResult<()> {
+        let chunk = Parser::with_limits(source, vm.limits()).parse().unwrap();
+        vm.interpret(chunk)
+    }
+
+    fn error_code(source: &str) -> Option<&'static str> {
+        run(&mut strict_vm(), source).unwrap_err().code
+    }
+
+    #[test]
+    fn endless_loop_hits_the_instruction_limit() {
+        assert_eq!(error_code("for i in 0..1000000000 {}"), Some("E023"));
+    }
+
+    #[test]
+    fn concatenation_bomb_hits_the_string_limit() {
+        assert_eq!(error_code("var s = \"x\"; for i in 0..64 s = s + s;"), Some("E023"));
+    }
+
+    // There are no functions to recurse with, so depth comes from nested
+    // expressions at compile time and from locals at runtime.
+    #[test]
+    fn deep_nesting_hits_the_limits() {
+        let nested = format!("print {}1{};", "(".repeat(100), ")".repeat(100));
+        let errors = Parser::with_limits(&nested, SandboxProfile::strict().limits).parse().err();
+        assert!(errors.is_some_and(|errors| errors.contains("E011")));
+
+        let locals: String = (0..100).map(|i| format!("var a{} = {};", i, i)).collect();
+        assert_eq!(error_code(&format!("{{ {} }}", locals)), Some("E023"));
+    }
+
+    #[test]
+    fn granted_access_is_taken_back() {
+        let mut vm = strict_vm();
+        run(&mut vm, "var fs = has_feature(\"fs\"); var env = has_native(\"env\");").unwrap();
+        assert_eq!(vm.get_global("fs"), Some(&Value::Bool(false)));
+        assert_eq!(vm.get_global("env"), Some(&Value::Bool(false)));
+
+        assert_eq!(error_code("read_file(\"secret.txt\");"), Some("E014"));
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+use crate::compiler::Parser;
+use crate::error::ErrorResult;
+use crate::limits::Limits;
+use crate::output::OutputSink;
+use crate::value::Value;
+use crate::vm::VM;
+
+// Compiles and runs many scripts with the same settings. String
+// constants are interned across every chunk the session compiles, so
+// names shared by the scripts are stored once. Each VM from new_vm starts
+// with fresh globals.
+pub struct Session {
+    limits: Limits,
+    strings: HashSet<Rc<String>>
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::with_limits(Limits::default())
+    }
+
+    pub fn with_limits(limits: Limits) -> Self {
+        Self {
+            limits,
+            strings: HashSet::new()
+        }
+    }
+
+    pub fn compile(&mut self, name: &str, source: &str) -> ErrorResult<Chunk> {
+        let mut chunk = Parser::with_limits(source, self.limits).parse()?;
+        chunk.set_name(name);
+
+        for constant in chunk.constants_mut() {
+            if let Value::String(string) = constant {
+                match self.strings.get(string) {
+                    Some(interned) => *string = interned.clone(),
+                    None => {
+                        self.strings.insert(string.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(chunk)
+    }
+
+    pub fn new_vm<O: OutputSink + 'static>(&self, output: O) -> VM {
+        VM::with_output(output).with_limits(self.limits)
+    }
+
+    // The number of distinct strings interned so far.
+    pub fn interned_strings(&self) -> usize {
+        self.strings.len()
+    }
+}
@@ -1,3 +1,7 @@
+use std::borrow::Cow;
+
+use crate::codes::{self, ErrorCode};
+
 #[derive(PartialEq, Eq, Clone)]
 pub enum TokenType {
     // Basic tokens
@@ -5,12 +9,16 @@ pub enum TokenType {
     RParen,
     LBrace,
     RBrace,
+    LBracket,
+    RBracket,
     Plus,
     Minus,
     Slash,
     Asterisk,
-    Coma,
+    Comma,
     Dot,
+    DotDot,
+    QuestionDot,
     Semicolon,
     
     // Comparison tokens
@@ -22,6 +30,7 @@ pub enum TokenType {
     GreaterEq,
     Eq,
     EqEq,
+    FatArrow,
 
     // Anything that requires extra information to
     // be attached with it
@@ -32,10 +41,13 @@ pub enum TokenType {
     And,
     Or,
     Class,
+    Defer,
     Super,
     This,
     If,
+    In,
     Else,
+    Match,
     True,
     False,
     Nil,
@@ -46,6 +58,9 @@ pub enum TokenType {
     Var,
     Print,
 
+    // A `//` comment, only produced when the tokenizer keeps comments
+    Comment,
+
     // An error token
     Error,
 
@@ -53,74 +68,218 @@ pub enum TokenType {
     EndOfFile
 }
 
+impl TokenType {
+    // How tokens of this type are always written. Empty for identifiers,
+    // literals, comments and errors, which carry their own text.
+    pub fn spelling(&self) -> &'static str {
+        match self {
+            TokenType::LParen => "(",
+            TokenType::RParen => ")",
+            TokenType::LBrace => "{",
+            TokenType::RBrace => "}",
+            TokenType::LBracket => "[",
+            TokenType::RBracket => "]",
+            TokenType::Plus => "+",
+            TokenType::Minus => "-",
+            TokenType::Slash => "/",
+            TokenType::Asterisk => "*",
+            TokenType::Comma => ",",
+            TokenType::Dot => ".",
+            TokenType::DotDot => "..",
+            TokenType::QuestionDot => "?.",
+            TokenType::Semicolon => ";",
+            TokenType::Bang => "!",
+            TokenType::BangEq => "!=",
+            TokenType::Less => "<",
+            TokenType::LessEq => "<=",
+            TokenType::Greater => ">",
+            TokenType::GreaterEq => ">=",
+            TokenType::Eq => "=",
+            TokenType::EqEq => "==",
+            TokenType::FatArrow => "=>",
+            TokenType::And => "and",
+            TokenType::Or => "or",
+            TokenType::Class => "class",
+            TokenType::Defer => "defer",
+            TokenType::Super => "super",
+            TokenType::This => "this",
+            TokenType::If => "if",
+            TokenType::In => "in",
+            TokenType::Else => "else",
+            TokenType::Match => "match",
+            TokenType::True => "true",
+            TokenType::False => "false",
+            TokenType::Nil => "nil",
+            TokenType::For => "for",
+            TokenType::While => "while",
+            TokenType::Fun => "fun",
+            TokenType::Return => "return",
+            TokenType::Var => "var",
+            TokenType::Print => "print",
+            TokenType::Ident | TokenType::String | TokenType::Number | TokenType::Comment
+                | TokenType::Error | TokenType::EndOfFile => ""
+        }
+    }
+
+    #[deprecated(note = "renamed to `TokenType::Comma`")]
+    #[allow(non_upper_case_globals)]
+    pub const Coma: TokenType = TokenType::Comma;
+}
+
 #[derive(Clone)]
-pub struct Token {
+pub struct Token<'src> {
     pub ty: TokenType,
-    pub text: String,
+    // Only tokens without a fixed spelling carry text. It borrows from
+    // the source, so cloning a token never copies it; only error
+    // messages made up by the tokenizer are owned.
+    text: Option<Cow<'src, str>>,
     pub line: usize,
+    // The code of an error token's message.
+    pub code: Option<&'static ErrorCode>
 }
 
-impl Token {
-    pub fn new(ty: TokenType, text: String, line: usize) -> Self {
+impl<'src> Token<'src> {
+    pub fn new(ty: TokenType, text: impl Into<Cow<'src, str>>, line: usize) -> Self {
         Self {
             ty,
-            text,
-            line
+            text: Some(text.into()),
+            line,
+            code: None
         }
     }
 
     pub fn new_no_text(ty: TokenType, line: usize) -> Self {
-        Self::new(ty, "".to_string(), line)
+        Self {
+            ty,
+            text: None,
+            line,
+            code: None
+        }
+    }
+
+    // The token's own text, or its type's spelling if it has none.
+    pub fn text(&self) -> &str {
+        match &self.text {
+            Some(text) => text,
+            None => self.ty.spelling()
+        }
+    }
+
+    // How error messages refer to the token, e.g. "identifier 'fo'",
+    // "';'" or "end of file".
+    pub fn describe(&self) -> String {
+        match self.ty {
+            TokenType::Ident => format!("identifier '{}'", self.text()),
+            TokenType::Number => format!("number {}", self.text()),
+            TokenType::String => format!("string \"{}\"", self.text()),
+            TokenType::Comment => "a comment".to_string(),
+            TokenType::Error => "an invalid token".to_string(),
+            TokenType::EndOfFile => "end of file".to_string(),
+            _ => format!("'{}'", self.ty.spelling())
+        }
+    }
+
+    // Like text(), but borrowed from the source rather than from the
+    // token, so it outlives the token. Text the tokenizer made up, such
+    // as an error message, gives the type's spelling instead.
+    pub fn source_text(&self) -> &'src str {
+        match &self.text {
+            Some(Cow::Borrowed(text)) => text,
+            _ => self.ty.spelling()
+        }
     }
 }
 
 #[derive(Clone)]
-pub struct Tokenizer {
+pub struct Tokenizer<'src> {
     current: usize,
     start: usize,
     line: usize,
-    source: String
+    source: &'src str,
+    comments: bool
 }
 
-impl Tokenizer {
-    pub fn new(source: &str) -> Self {
+impl<'src> Tokenizer<'src> {
+    pub fn new(source: &'src str) -> Self {
         Self {
             current: 0,
             start: 0,
             line: 1,
-            source: source.to_string()
+            source,
+            comments: false
         }
     }
 
+    // Emits comments as `Comment` tokens instead of skipping them, for
+    // tools that need to reproduce the source such as the formatter.
+    pub fn with_comments(mut self, comments: bool) -> Self {
+        self.comments = comments;
+        self
+    }
+
+    // Starts scanning the same source again from the beginning.
+    pub fn reset(&mut self) {
+        self.current = 0;
+        self.start = 0;
+        self.line = 1;
+    }
+
+    // Scans the next token without consuming it.
+    pub fn peek_token(&mut self) -> Token<'src> {
+        let (current, start, line) = (self.current, self.start, self.line);
+        let token = self.scan_token();
+
+        self.current = current;
+        self.start = start;
+        self.line = line;
+        token
+    }
+
+    // Whether the token just scanned is a comma written between two
+    // digits, as in `1,5`, which is likely a decimal comma.
+    pub fn is_decimal_comma(&self) -> bool {
+        let bytes = self.source.as_bytes();
+
+        self.start > 0
+            && bytes.get(self.start) == Some(&b',')
+            && bytes[self.start - 1].is_ascii_digit()
+            && bytes.get(self.start + 1).is_some_and(u8::is_ascii_digit)
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
     
+    // Positions are byte offsets into the source, always kept on
+    // character boundaries.
     fn get_char(&self, idx: usize) -> char {
         if idx >= self.source.len() {
             '\0'
         } else {
-            self.source.chars()
-                .nth(idx)
+            self.source[idx..].chars()
+                .next()
                 .unwrap()
         }
     }
 
-    fn make_token(&self, ty: TokenType) -> Token {
+    fn make_token(&self, ty: TokenType) -> Token<'src> {
         Token::new_no_text(ty, self.line)
     }
 
-    fn make_token_text(&self, ty: TokenType, text: &str) -> Token {
-        Token::new(ty, text.to_string(), self.line)
+    fn make_token_text(&self, ty: TokenType, text: &'src str) -> Token<'src> {
+        Token::new(ty, text, self.line)
     }
 
-    fn make_error(&self, error: &str) -> Token {
-        self.make_token_text(TokenType::Error, error)
+    fn make_error(&self, code: &'static ErrorCode, error: impl Into<Cow<'src, str>>) -> Token<'src> {
+        let mut token = Token::new(TokenType::Error, error, self.line);
+        token.code = Some(code);
+        token
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.get_char(self.current - 1)
+        let character = self.peek();
+        self.current += character.len_utf8();
+        character
     }
 
     fn peek(&self) -> char {
@@ -128,7 +287,7 @@ impl Tokenizer {
     }
 
     fn peek_next(&self) -> char {
-        self.get_char(self.current + 1)
+        self.get_char(self.current + self.peek().len_utf8())
     }
 
     fn skip_whitespace(&mut self) {
@@ -140,7 +299,7 @@ impl Tokenizer {
             } else if character == '\n' {
                 self.line += 1;
                 self.advance(); 
-            } else if character == '/' && self.peek_next() == '/' {
+            } else if self.at_comment() && !self.comments {
                 while self.peek() != '\n' && !self.is_at_end() {
                     self.advance();
                 }
@@ -150,21 +309,31 @@ impl Tokenizer {
         }
     }
 
+    // A `//` comment, or a `#!` line at the very start of the source so
+    // scripts can be run directly, which is read as a comment.
+    fn at_comment(&self) -> bool {
+        match self.peek() {
+            '/' => self.peek_next() == '/',
+            '#' => self.current == 0 && self.peek_next() == '!',
+            _ => false
+        }
+    }
+
     fn match_char(&mut self, expected: char) -> bool {
         if self.is_at_end() {
             return false
         }
 
-        if self.source.chars().nth(self.current).unwrap() != expected {
+        if self.peek() != expected {
             return false
         }
 
-        self.current += 1;
+        self.current += expected.len_utf8();
 
         true
     }
 
-    pub fn scan_token(&mut self) -> Token {
+    pub fn scan_token(&mut self) -> Token<'src> {
         self.skip_whitespace();
 
         self.start = self.current;
@@ -185,35 +354,68 @@ impl Tokenizer {
                     self.advance();
                 }
 
+                // `5.` is rejected, while `5..` stays a range and `5.name`
+                // a method call. A leading dot as in `.5` is left to the
+                // parser, which explains it.
                 if self.peek() == '.' && Self::is_digit(self.peek_next()) {
                     self.advance();
 
                     while Self::is_digit(self.peek()) {
                         self.advance();
                     }
+
+                    if self.peek() == '.' && Self::is_digit(self.peek_next()) {
+                        while self.peek() == '.' || Self::is_digit(self.peek()) {
+                            self.advance();
+                        }
+
+                        return self.make_error(codes::MALFORMED_NUMBER, "A number can't have more than one decimal point.")
+                    }
+                } else if self.peek() == '.' && self.peek_next() != '.' && !Self::is_identifier_start(self.peek_next()) {
+                    self.advance();
+                    return self.make_error(codes::MALFORMED_NUMBER, "Expected digits after the decimal point.")
                 }
-                
+
                 let text = &self.source[self.start..self.current];
                 self.make_token_text(TokenType::Number, text)
             },
-            c if Self::is_alpha(c) => {
-                while Self::is_alpha(self.peek()) || Self::is_digit(self.peek()) {
+            c if Self::is_identifier_start(c) => {
+                while Self::is_identifier_continue(self.peek()) {
                     self.advance();
                 }
 
                 let text = &self.source[self.start..self.current];
-                let token_type = Self::identifier_type(&self.source[self.start..self.current]);
-                return self.make_token_text(token_type, text)
+                match Self::identifier_type(text) {
+                    TokenType::Ident => self.make_token_text(TokenType::Ident, text),
+                    keyword => self.make_token(keyword)
+                }
             },
             '(' => self.make_token(TokenType::LParen),
             ')' => self.make_token(TokenType::RParen),
             '{' => self.make_token(TokenType::LBrace),
             '}' => self.make_token(TokenType::RBrace),
+            '[' => self.make_token(TokenType::LBracket),
+            ']' => self.make_token(TokenType::RBracket),
             '+' => self.make_token(TokenType::Plus),
             '-' => self.make_token(TokenType::Minus),
             '*' => self.make_token(TokenType::Asterisk),
-            '/' => self.make_token(TokenType::Slash),
+            '/' => if self.peek() == '/' {
+                self.comment()
+            } else {
+                self.make_token(TokenType::Slash)
+            },
             ';' => self.make_token(TokenType::Semicolon),
+            ',' => self.make_token(TokenType::Comma),
+            '.' => if self.match_char('.') {
+                self.make_token(TokenType::DotDot)
+            } else {
+                self.make_token(TokenType::Dot)
+            },
+            '?' => if self.match_char('.') {
+                self.make_token(TokenType::QuestionDot)
+            } else {
+                self.make_error(codes::UNEXPECTED_CHARACTER, "Unexpected character '?'.")
+            },
             '!' => if self.match_char('=') {
                 self.make_token(TokenType::BangEq)
             } else {
@@ -221,6 +423,8 @@ impl Tokenizer {
             },
             '=' => if self.match_char('=') {
                 self.make_token(TokenType::EqEq)
+            } else if self.match_char('>') {
+                self.make_token(TokenType::FatArrow)
             } else {
                 self.make_token(TokenType::Eq)
             },
@@ -235,13 +439,14 @@ impl Tokenizer {
                 self.make_token(TokenType::Greater)
             },
             '"' => self.string(),
+            '#' if self.start == 0 && self.peek() == '!' => self.comment(),
             _ => {
-                self.make_error(&format!("Unexpected character '{}'", character))
+                self.make_error(codes::UNEXPECTED_CHARACTER, format!("Unexpected character '{}'.", character))
             }
         }
     }
 
-    fn string(&mut self) -> Token {
+    fn string(&mut self) -> Token<'src> {
         while self.peek() != '"' && !self.is_at_end() {
             if self.peek() == '\n' {
                 self.line += 1;
@@ -251,23 +456,35 @@ impl Tokenizer {
         }
 
         if self.is_at_end() {
-            return self.make_error("Unterminated string")
+            return self.make_error(codes::UNTERMINATED_STRING, "Unterminated string.")
         }
 
         self.advance();
-        let text = self.source[self.start+1..self.current-1].to_string();
-        return Token::new(TokenType::String, text, self.line)
+        let text = &self.source[self.start+1..self.current-1];
+        self.make_token_text(TokenType::String, text)
+    }
+
+    fn comment(&mut self) -> Token<'src> {
+        while self.peek() != '\n' && !self.is_at_end() {
+            self.advance();
+        }
+
+        let text = self.source[self.start..self.current].trim_end();
+        self.make_token_text(TokenType::Comment, text)
     }
 
     fn identifier_type(content: &str) -> TokenType {
         match content {
             "and" => TokenType::And,
             "class" => TokenType::Class,
+            "defer" => TokenType::Defer,
             "else" => TokenType::Else,
             "false" => TokenType::False,
             "for" => TokenType::For,
             "fun" => TokenType::Fun,
             "if" => TokenType::If,
+            "in" => TokenType::In,
+            "match" => TokenType::Match,
             "nil" => TokenType::Nil,
             "or" => TokenType::Or,
             "print" => TokenType::Print,
@@ -281,13 +498,18 @@ impl Tokenizer {
         }
     }
 
-    fn is_alpha(c: char) -> bool {
-        (c >= 'a' && c <= 'z') ||
-        (c >= 'A' && c <= 'Z') ||
-        c == '_'
+    // Identifiers follow Unicode's XID rules plus a leading underscore, so
+    // `naïve` and `变量` are single identifiers. Keywords stay ASCII and
+    // case sensitive.
+    fn is_identifier_start(c: char) -> bool {
+        c == '_' || unicode_ident::is_xid_start(c)
+    }
+
+    fn is_identifier_continue(c: char) -> bool {
+        unicode_ident::is_xid_continue(c)
     }
 
     fn is_digit(c: char) -> bool {
-        c >= '0' && c <= '9'
+        c.is_ascii_digit()
     }
 }
\ No newline at end of file
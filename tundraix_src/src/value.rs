@@ -1,14 +1,116 @@
-use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
 
-#[derive(PartialEq, Clone)]
+use crate::codes;
+use crate::error::{ErrorResult, NativeResult};
+use crate::natives::NativeFn;
+
+// The longest string concatenation and the string natives may produce.
+pub const MAX_STRING_LENGTH: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy)]
+pub struct NativeFunction {
+    pub name: &'static str,
+    pub function: NativeFn
+}
+
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name && std::ptr::fn_addr_eq(self.function, other.function)
+    }
+}
+
+impl Debug for NativeFunction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "NativeFunction({})", self.name)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for NativeFunction {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name)
+    }
+}
+
+pub type ClosureFn = dyn Fn(&[Value]) -> ErrorResult<Value>;
+
+// A Rust closure handed to a script as a value, made with
+// Value::from_native. Scripts can store, pass around and call it like a
+// native. It is shared rather than copied, so state it changes must sit
+// in a Cell or RefCell.
+#[derive(Clone)]
+pub struct NativeClosure {
+    pub name: Rc<str>,
+    pub function: Rc<ClosureFn>
+}
+
+// Two closure values are equal when they are the same closure.
+impl PartialEq for NativeClosure {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::addr_eq(Rc::as_ptr(&self.function), Rc::as_ptr(&other.function))
+    }
+}
+
+impl Debug for NativeClosure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "NativeClosure({})", self.name)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for NativeClosure {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name)
+    }
+}
+
+// Memory strategy: heap payloads are reference counted with `Rc`, so
+// cloning a Value never copies its contents and a payload is freed as
+// soon as the last Value pointing at it is dropped. Strings are
+// immutable and can't reference other values, so they can never form
+// a cycle. Container values that can reference each other must either
+// hold `Weak` back-references or move to a traced heap owned by the VM;
+// plain `Rc` cycles between them would leak.
+#[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum Value {
     Bool(bool),
     Number(f64),
-    String(String),
+    String(Rc<String>),
+    Native(Rc<NativeFunction>),
+    Closure(Rc<NativeClosure>),
     Nil
 }
 
+// Every payload sits behind a thin Rc so a Value is one pointer plus
+// its tag; the VM moves values around constantly. Strings pay for this
+// with a second indirection, since Rc<str> is a fat pointer.
+const _: () = assert!(std::mem::size_of::<Value>() <= 16);
+
 impl Value {
+    pub fn from_native<F>(name: &str, function: F) -> Self
+    where
+        F: Fn(&[Value]) -> ErrorResult<Value> + 'static
+    {
+        Value::Closure(Rc::new(NativeClosure {
+            name: name.into(),
+            function: Rc::new(function)
+        }))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Native(_) | Value::Closure(_) => "native function",
+            Value::Nil => "nil"
+        }
+    }
+
     pub fn as_bool(&self) -> bool {
         if let Self::Bool(v) = self {
             return *v;
@@ -25,7 +127,7 @@ impl Value {
         unreachable!()
     }
 
-    pub fn as_string(&self) -> String {
+    pub fn as_string(&self) -> Rc<String> {
         if let Self::String(v) = self {
             return v.clone();
         }
@@ -38,7 +140,7 @@ impl Value {
             return true;
         }
 
-        return false;
+        false
     }
 
     pub fn is_number(&self) -> bool {
@@ -46,7 +148,7 @@ impl Value {
             return true;
         }
 
-        return false;
+        false
     }
 
     pub fn is_string(&mut self) -> bool {
@@ -54,7 +156,7 @@ impl Value {
             return true;
         }
 
-        return false;
+        false
     }
 
     pub fn is_nil(&self) -> bool {
@@ -62,7 +164,71 @@ impl Value {
             return true;
         }
 
-        return false;
+        false
+    }
+
+    // Truthiness, used by `!`, conditions and bool(): only nil and false
+    // are false. Every number, 0 included, and every string, "" included,
+    // is true, as are functions.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    // The form used by debugging output such as the disassembler and
+    // dump_state, where "2" and 2 or "nil" and nil must look different.
+    // Strings are quoted with quotes, backslashes and control characters
+    // escaped. Display gives the bare form used by print and `+`.
+    pub fn repr(&self) -> String {
+        match self {
+            Value::String(v) => format!("\"{}\"", v.escape_debug()),
+            _ => self.to_string()
+        }
+    }
+
+    // The hash of a value that can be used as a map key. Functions and
+    // NaN can't be.
+    pub fn try_hash(&self) -> NativeResult<u64> {
+        match self {
+            Value::Number(v) if v.is_nan() => return Err(codes::INVALID_KEY.error("NaN can't be used as a key.")),
+            Value::Native(_) | Value::Closure(_) => {
+                return Err(codes::INVALID_KEY.error(format!("A {} can't be used as a key.", self.type_name())))
+            },
+            _ => {}
+        }
+
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+}
+
+// Hashing follows ==: 0 and -0 hash the same and strings hash by their
+// contents. NaN isn't equal to anything, itself included, so looking a
+// NaN key up never finds it; try_hash refuses it instead.
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+
+        match self {
+            Value::Bool(v) => v.hash(state),
+            Value::Number(v) => {
+                let bits = if *v == 0.0 {
+                    0.0f64.to_bits()
+                } else if v.is_nan() {
+                    f64::NAN.to_bits()
+                } else {
+                    v.to_bits()
+                };
+
+                bits.hash(state)
+            },
+            Value::String(v) => v.hash(state),
+            Value::Native(v) => v.name.hash(state),
+            Value::Closure(v) => v.name.hash(state),
+            Value::Nil => {}
+        }
     }
 }
 
@@ -78,6 +244,12 @@ impl Display for Value {
             Value::String(v) => {
                 write!(f, "{}", v)
             },
+            Value::Native(v) => {
+                write!(f, "<native fn {}>", v.name)
+            },
+            Value::Closure(v) => {
+                write!(f, "<native fn {}>", v.name)
+            },
             Value::Nil => {
                 write!(f, "nil")
             }
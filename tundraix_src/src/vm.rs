@@ -1,133 +1,1116 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::convert::TryFrom;
 use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
 
-use std::convert::TryFrom;
+use crate::chunk::{Chunk, DecodedInstruction, OpCode, Byte};
+use crate::disassembler::disassemble_instruction;
+use crate::globals::{Globals, Symbol};
+use crate::limits::Limits;
+use crate::codes::{self, ErrorCode};
+use crate::error::{ErrorResult, Error, HistoryEntry, NativeError, NativeResult, RuntimeError, RuntimeResult, TraceFrame};
+use crate::exec::{self, ExecDecision, ExecOutput, ExecPolicyFn};
+use crate::methods::find_method;
+use crate::natives::{NativeFn, ENV_NATIVES, EXEC_NATIVES, FEATURES, FS_NATIVES, STANDARD_NATIVES};
+use crate::output::{Level, LogFn, OutputSink, PrintErrorPolicy, PrintFn};
+use crate::profiler::{LineProfile, Profiler};
+use crate::sandbox::SandboxProfile;
+use crate::value::{NativeFunction, Value};
+
+// How many of the most recently executed instructions are kept while
+// the history is turned on.
+const RECENT_INSTRUCTIONS: usize = 16;
+const DUMP_STRING_LIMIT: usize = 60;
+// The most values a single instruction pushes, so the stack check can
+// run once per instruction instead of on every push.
+const STACK_HEADROOM: usize = 2;
+// Reading the clock on every instruction would dominate simple loops.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+// Tells VMs apart so a Symbol can't be used with the wrong one.
+static NEXT_VM_ID: AtomicU32 = AtomicU32::new(0);
+
+// Counts of the heap payloads reachable from the VM's stack, globals and
+// current chunk. Payloads shared between several values are counted once.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct HeapStats {
+    pub strings: usize,
+    // Each string's Rc allocation, which holds the two reference counts
+    // and the String, plus the String's buffer capacity. Allocator
+    // overhead isn't counted.
+    pub string_bytes: usize
+}
 
-use crate::chunk::{Chunk, OpCode, Byte};
-use crate::error::{ErrorResult, Error};
-use crate::value::Value;
+// One expect_eq or expect_error check made by a script.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TestResult {
+    pub label: String,
+    pub line: usize,
+    // Why the check failed, or None if it passed.
+    pub failure: Option<String>
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+pub type WatchFn = Box<dyn FnMut(Option<&Value>, &Value, usize) -> ErrorResult<()>>;
+pub type LineHookFn = Box<dyn FnMut(usize) -> ErrorResult<()>>;
 
-type Table = std::collections::HashMap<String, Value>;
-type PrintFn = fn(String) -> ErrorResult<()>;
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WatchHandle(usize);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RunStatus {
+    Done,
+    // The instruction budget ran out. Calling run_for again resumes
+    // exactly where execution stopped.
+    Paused,
+    // A native such as wait() asked to suspend for this long. The host
+    // decides when to call run_for again; execution resumes right after
+    // the call, which returns nil.
+    Waiting(Duration)
+}
+
+struct Watcher {
+    handle: WatchHandle,
+    name: String,
+    callback: WatchFn
+}
+
+// The globals defined by the last two loads of one reloadable script.
+#[derive(Default)]
+struct ReloadRecord {
+    previous: BTreeSet<String>,
+    current: BTreeSet<String>
+}
 
 pub struct VM {
+    id: u32,
     chunk: Chunk,
     ip: usize,
     current_instruction: Byte,
-    stack: [Value; 256],
+    stack: Vec<Value>,
     stack_top: usize,
-    globals: Table,
-    print_fn: PrintFn
+    globals: Globals,
+    output: Box<dyn OutputSink>,
+    print_error_policy: PrintErrorPolicy,
+    watchers: Vec<Watcher>,
+    next_watch_handle: usize,
+    instruction_count: u64,
+    instruction_limit: u64,
+    history: bool,
+    recent_offsets: VecDeque<usize>,
+    profiler: Option<Profiler>,
+    deadline: Option<Instant>,
+    time_limit: Option<Duration>,
+    limits: Limits,
+    args: Vec<String>,
+    fs_root: Option<PathBuf>,
+    env_allowlist: Vec<String>,
+    exec_policy: Option<ExecPolicyFn>,
+    // The result of the last command exec() ran or was replied to with.
+    last_exec: Option<ExecOutput>,
+    log_fn: Option<LogFn>,
+    line_hook: Option<LineHookFn>,
+    // Set by suspend() during a native call.
+    pending_wait: Option<Duration>,
+    // The line last reported to the line hook.
+    hook_line: Option<usize>,
+    reloads: HashMap<String, ReloadRecord>,
+    // The tag and the globals defined so far between begin_reload and
+    // end_reload.
+    recording: Option<(String, BTreeSet<String>)>,
+    test_results: Vec<TestResult>,
+    // Deferred bodies registered by DEFER and not yet run, newest last.
+    defers: Vec<PendingDefer>,
+    // Where each running deferred body returns to when it ends.
+    defer_returns: Vec<usize>
 }
 
+// A deferred body and the stack depth it was registered at, which is
+// where its locals resolve from.
+struct PendingDefer {
+    body: usize,
+    stack_top: usize
+}
+
+// Stands in for a return address while a deferred body runs during
+// unwinding, so its END_DEFER stops execution instead.
+const UNWINDING: usize = usize::MAX;
+
+// The largest whole number a for loop counter can count up to exactly.
+const MAX_RANGE_BOUND: f64 = 9_007_199_254_740_992.0;
+
+// The size of an Rc<String> allocation: the strong and weak counts,
+// then the String itself.
+const RC_STRING_SIZE: usize = 2 * std::mem::size_of::<usize>() + std::mem::size_of::<String>();
+
 impl VM {
     pub fn new(print_fn: PrintFn) -> Self {
-        Self {
+        Self::with_output(print_fn)
+    }
+
+    pub fn with_output<O: OutputSink + 'static>(output: O) -> Self {
+        let mut vm = Self {
+            id: NEXT_VM_ID.fetch_add(1, Ordering::Relaxed),
             chunk: Chunk::new(),
             ip: 0,
             current_instruction: Byte::new(0, 0),
-            stack: [(); 256].map(|_| Value::Nil),
+            stack: Vec::new(),
             stack_top: 0,
-            globals: std::collections::HashMap::new(),
-            print_fn
+            globals: Globals::default(),
+            output: Box::new(output),
+            print_error_policy: PrintErrorPolicy::default(),
+            watchers: Vec::new(),
+            next_watch_handle: 0,
+            instruction_count: 0,
+            instruction_limit: u64::MAX,
+            history: false,
+            recent_offsets: VecDeque::with_capacity(RECENT_INSTRUCTIONS),
+            profiler: None,
+            deadline: None,
+            time_limit: None,
+            limits: Limits::default(),
+            args: Vec::new(),
+            fs_root: None,
+            env_allowlist: Vec::new(),
+            exec_policy: None,
+            last_exec: None,
+            log_fn: None,
+            line_hook: None,
+            pending_wait: None,
+            hook_line: None,
+            reloads: HashMap::new(),
+            recording: None,
+            test_results: Vec::new(),
+            defers: Vec::new(),
+            defer_returns: Vec::new()
+        };
+
+        vm.globals.reserve(STANDARD_NATIVES.len());
+        for (name, function) in STANDARD_NATIVES {
+            vm.define_native(name, *function);
+        }
+
+        vm
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits.clamped();
+        self
+    }
+
+    // Makes room up front for `globals` globals, natives included, and
+    // `stack` stack values, so a VM that only runs small scripts doesn't
+    // grow either. Both still grow past that as needed. The stack is
+    // otherwise allocated as it fills.
+    pub fn with_capacity(mut self, globals: usize, stack: usize) -> Self {
+        self.globals.reserve(globals);
+        self.stack.reserve(stack.min(self.limits.stack + STACK_HEADROOM).saturating_sub(self.stack.len()));
+        self
+    }
+
+    // Applies all of a sandbox profile's settings and takes back any file
+    // system, environment or command access granted earlier, along with
+    // the natives it registered. See SandboxProfile for what each preset
+    // guarantees.
+    pub fn sandboxed(mut self, profile: SandboxProfile) -> Self {
+        self.fs_root = None;
+        self.env_allowlist.clear();
+        self.exec_policy = None;
+        for (name, _) in FEATURES.iter().flat_map(|(_, natives)| natives.iter()) {
+            self.globals.remove(name);
+        }
+
+        self.set_instruction_limit(Some(profile.instruction_limit));
+        self.set_time_limit(Some(profile.time_limit));
+        self.with_limits(profile.limits)
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    // The script's command line arguments, read by the arg natives.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn set_print_error_policy(&mut self, policy: PrintErrorPolicy) {
+        self.print_error_policy = policy;
+    }
+
+    // Receives the messages of the log_* natives, which do nothing
+    // until a hook is set.
+    pub fn set_log_fn<F>(&mut self, log_fn: F)
+    where
+        F: FnMut(Level, &str) + 'static
+    {
+        self.log_fn = Some(Box::new(log_fn));
+    }
+
+    // For natives: stops execution once the current native call returns.
+    // run_for reports RunStatus::Waiting with the duration; interpret and
+    // run sleep for it instead, failing if that would pass the deadline.
+    pub fn suspend(&mut self, wait: Duration) {
+        self.pending_wait = Some(wait);
+    }
+
+    // For natives: records the outcome of a check made at the current
+    // line, without stopping the script.
+    pub fn record_test(&mut self, label: String, failure: Option<String>) {
+        self.test_results.push(TestResult {
+            label,
+            line: self.current_instruction.line,
+            failure
+        });
+    }
+
+    // Every check recorded since the VM was created, in order.
+    pub fn test_results(&self) -> &[TestResult] {
+        &self.test_results
+    }
+
+    pub fn log(&mut self, level: Level, message: &str) {
+        if let Some(log_fn) = &mut self.log_fn {
+            log_fn(level, message);
+        }
+    }
+
+    // Scripts get no file or environment access unless the host grants
+    // it. Granting registers the natives; the file natives only reach
+    // paths inside `root`.
+    pub fn allow_fs(&mut self, root: impl AsRef<Path>) -> ErrorResult<()> {
+        let root = root.as_ref();
+        let root = root.canonicalize()
+            .map_err(|error| format!("Could not use '{}' as the file root: {}", root.display(), error))?;
+
+        self.fs_root = Some(root);
+        for (name, function) in FS_NATIVES {
+            self.define_native(name, *function);
+        }
+
+        Ok(())
+    }
+
+    pub fn allow_env(&mut self, allowlist: Vec<String>) {
+        self.env_allowlist = allowlist;
+        for (name, function) in ENV_NATIVES {
+            self.define_native(name, *function);
+        }
+    }
+
+    // Registers exec(). Every call goes through `policy` first, which can
+    // let it run, deny it or answer it without running anything. A
+    // running command blocks the VM, and the time limit isn't checked
+    // until it finishes.
+    pub fn allow_exec<F>(&mut self, policy: F)
+    where
+        F: FnMut(&str, &[String]) -> ExecDecision + 'static
+    {
+        self.exec_policy = Some(Box::new(policy));
+        for (name, function) in EXEC_NATIVES {
+            self.define_native(name, *function);
+        }
+    }
+
+    // For natives: runs a command if the exec policy allows it.
+    pub fn exec(&mut self, command: &str, args: &[String]) -> NativeResult<ExecOutput> {
+        let policy = self.exec_policy.as_mut().ok_or_else(|| codes::ACCESS_DENIED.error("exec() is not allowed."))?;
+
+        // The policy's reason is the host's own error, so it has no code.
+        match policy(command, args) {
+            ExecDecision::Run => exec::run(command, args),
+            ExecDecision::Deny(reason) => Err(format!("exec() was not allowed to run '{}': {}", command, reason).into()),
+            ExecDecision::Reply(output) => Ok(output)
         }
     }
 
+    // For natives: the result of the last exec() call.
+    pub fn last_exec(&self) -> Option<&ExecOutput> {
+        self.last_exec.as_ref()
+    }
+
+    pub fn set_last_exec(&mut self, output: Option<ExecOutput>) {
+        self.last_exec = output;
+    }
+
+    pub fn fs_root(&self) -> Option<&Path> {
+        self.fs_root.as_deref()
+    }
+
+    pub fn env_allowed(&self, name: &str) -> bool {
+        self.env_allowlist.iter().any(|allowed| allowed == name)
+    }
+
+    pub fn has_native(&self, name: &str) -> bool {
+        matches!(self.globals.get(name), Some(Value::Native(_) | Value::Closure(_)))
+    }
+
+    // The optional features whose natives are registered on this VM.
+    pub fn features(&self) -> Vec<&'static str> {
+        FEATURES.iter()
+            .filter(|(_, natives)| natives.iter().all(|(name, _)| self.has_native(name)))
+            .map(|(feature, _)| *feature)
+            .collect()
+    }
+
+    pub fn define_native(&mut self, name: &'static str, function: NativeFn) {
+        self.globals.insert(name, Value::Native(Rc::new(NativeFunction { name, function })));
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(name)
+    }
+
+    // Every defined global, natives included, in the order their names
+    // were first seen.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.globals.iter()
+    }
+
+    // The named locals in scope where execution stopped, e.g. after
+    // run_for pauses, in slot order. Empty unless the chunk was compiled
+    // with debug info.
+    pub fn locals(&self) -> Vec<(&str, &Value)> {
+        (0..self.stack_top)
+            .filter_map(|slot| {
+                let name = self.chunk.local_name(u8::try_from(slot).ok()?, self.ip)?;
+                Some((name, &self.stack[slot]))
+            })
+            .collect()
+    }
+
+    // Defines or overwrites a global from the host. Watchers only see
+    // writes made by scripts.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(name, value);
+    }
+
+    // Resolves a global's name once, for repeated get_global_sym and
+    // set_global_sym calls that skip hashing the name.
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        Symbol {
+            vm: self.id,
+            index: self.globals.intern(name)
+        }
+    }
+
+    pub fn get_global_sym(&self, symbol: Symbol) -> Option<&Value> {
+        debug_assert_eq!(symbol.vm, self.id, "Symbol used with a VM that didn't intern it.");
+        self.globals.get_at(symbol.index)
+    }
+
+    pub fn set_global_sym(&mut self, symbol: Symbol, value: Value) {
+        debug_assert_eq!(symbol.vm, self.id, "Symbol used with a VM that didn't intern it.");
+        self.globals.set_at(symbol.index, value);
+    }
+
+    // Called with the line number each time execution moves on to an
+    // instruction from a different line than the one before, so once per
+    // run of instructions on the same line. Returning an error stops
+    // execution before that line runs.
+    pub fn set_line_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(usize) -> ErrorResult<()> + 'static
+    {
+        self.line_hook = Some(Box::new(hook));
+        self.hook_line = None;
+    }
+
+    pub fn clear_line_hook(&mut self) {
+        self.line_hook = None;
+    }
+
+    // The callback receives the previous value (`None` when the global
+    // is being defined for the first time), the new value and the line
+    // of the write. Returning an error stops execution before the write.
+    pub fn watch_global<F>(&mut self, name: &str, callback: F) -> WatchHandle
+    where
+        F: FnMut(Option<&Value>, &Value, usize) -> ErrorResult<()> + 'static
+    {
+        let handle = WatchHandle(self.next_watch_handle);
+        self.next_watch_handle += 1;
+
+        self.watchers.push(Watcher {
+            handle,
+            name: name.to_string(),
+            callback: Box::new(callback)
+        });
+
+        handle
+    }
+
+    pub fn unwatch_global(&mut self, handle: WatchHandle) -> bool {
+        let count = self.watchers.len();
+        self.watchers.retain(|watcher| watcher.handle != handle);
+        self.watchers.len() != count
+    }
+
+    // Hot reloading: wrap each run of a script's new version in
+    // begin_reload and end_reload with the same tag, then call
+    // sweep_reload to undefine the globals the previous version defined
+    // and the new one no longer does. Values those globals held stay
+    // alive as long as something else still refers to them.
+    pub fn begin_reload(&mut self, tag: &str) -> ErrorResult<()> {
+        if let Some((active, _)) = &self.recording {
+            return Err(format!("Can't begin reload '{}' while '{}' is still recording.", tag, active));
+        }
+
+        self.recording = Some((tag.to_string(), BTreeSet::new()));
+        Ok(())
+    }
+
+    pub fn end_reload(&mut self, tag: &str) -> ErrorResult<()> {
+        let defined = match self.recording.take() {
+            Some((active, defined)) if active == tag => defined,
+            Some((active, defined)) => {
+                let error = format!("Can't end reload '{}' while '{}' is recording.", tag, active);
+                self.recording = Some((active, defined));
+                return Err(error)
+            },
+            None => return Err(format!("Reload '{}' was never begun.", tag))
+        };
+
+        let record = self.reloads.entry(tag.to_string()).or_default();
+        record.previous = std::mem::replace(&mut record.current, defined);
+        Ok(())
+    }
+
+    // Returns the names of the globals that were undefined.
+    pub fn sweep_reload(&mut self, tag: &str) -> Vec<String> {
+        let record = match self.reloads.get_mut(tag) {
+            Some(record) => record,
+            None => return Vec::new()
+        };
+
+        let stale: Vec<String> = record.previous.difference(&record.current).cloned().collect();
+        record.previous = record.current.clone();
+
+        stale.into_iter()
+            .filter(|name| self.globals.remove(name))
+            .collect()
+    }
+
     pub fn pop_value(&mut self) -> Value {
         self.stack_top -= 1;
-        self.stack[self.stack_top].clone()
+        std::mem::replace(&mut self.stack[self.stack_top], Value::Nil)
     }
 
     pub fn push_value(&mut self, value: Value) {
-        self.stack[self.stack_top] = value;
+        if self.stack_top < self.stack.len() {
+            self.stack[self.stack_top] = value;
+        } else {
+            self.stack.push(value);
+        }
+
         self.stack_top += 1;
     }
 
-    pub fn interpret(&mut self, chunk: Chunk) -> ErrorResult<()> {
+    pub fn interpret(&mut self, chunk: Chunk) -> RuntimeResult<()> {
+        self.load(chunk);
+        self.finish(None).map(|_| ())
+    }
+
+    // Returns the VM to the state it was created in, keeping its settings,
+    // watchers and allocations so a pool can hand it out again. Every
+    // global is removed and the standard natives are defined afresh, so
+    // natives the host added must be defined again. Names stay interned,
+    // so symbols from intern remain valid.
+    pub fn clear(&mut self) {
+        self.chunk = Chunk::new();
+        self.ip = 0;
+        self.stack_top = 0;
+        self.stack.clear();
+        self.globals.clear();
+        self.instruction_count = 0;
+        self.recent_offsets.clear();
+        self.hook_line = None;
+        self.pending_wait = None;
+        self.deadline = None;
+        self.reloads.clear();
+        self.recording = None;
+        self.test_results.clear();
+        self.last_exec = None;
+        self.defers.clear();
+        self.defer_returns.clear();
+
+        if let Some(profiler) = &mut self.profiler {
+            *profiler = Profiler::default();
+        }
+
+        for (name, function) in STANDARD_NATIVES {
+            self.define_native(name, *function);
+        }
+    }
+
+    // Prepares a chunk for run_for without executing any of it.
+    pub fn load(&mut self, chunk: Chunk) {
         self.chunk = chunk;
         self.ip = 0;
-        self.run()
+        // A failed run leaves its values behind, and locals are addressed
+        // from the bottom of the stack.
+        self.stack_top = 0;
+        self.instruction_count = 0;
+        self.recent_offsets.clear();
+        self.hook_line = None;
+        self.pending_wait = None;
+        self.defers.clear();
+        self.defer_returns.clear();
+
+        if let Some(time_limit) = self.time_limit {
+            self.deadline = Some(Instant::now() + time_limit);
+        }
+    }
+
+    // Executes at most `max_instructions` instructions of the loaded
+    // chunk, so a host can spread a script over several frames. Output is
+    // flushed before returning either way.
+    pub fn run_for(&mut self, max_instructions: u32) -> RuntimeResult<RunStatus> {
+        self.finish(Some(max_instructions as u64))
+    }
+
+    fn finish(&mut self, budget: Option<u64>) -> RuntimeResult<RunStatus> {
+        let result = self.execute(budget);
+
+        if let Err(error) = &result {
+            self.unwind_defers(error);
+        }
+
+        if let Some(profiler) = &mut self.profiler {
+            profiler.pause();
+        }
+
+        let flushed = self.flush_output();
+        let status = result?;
+        flushed?;
+        Ok(status)
+    }
+
+    // Runs the deferred bodies still pending when a runtime error ends
+    // the script, newest first, ignoring the instruction budget. The
+    // original error is the one reported: a body that fails here is
+    // abandoned and the rest still run. Nothing runs after a runtime
+    // limit is hit, since the bodies would only hit it again.
+    fn unwind_defers(&mut self, error: &RuntimeError) {
+        let (ip, stack_top) = (self.ip, self.stack_top);
+
+        if error.code == Some(codes::RUNTIME_LIMIT.code) {
+            self.defers.clear();
+        }
+
+        while let Some(deferred) = self.defers.pop() {
+            self.defer_returns.clear();
+            self.defer_returns.push(UNWINDING);
+            self.stack_top = deferred.stack_top;
+            self.ip = deferred.body;
+
+            let _ = self.execute(None);
+        }
+
+        self.defer_returns.clear();
+        self.ip = ip;
+        self.stack_top = stack_top;
+    }
+
+    // Walks the values reachable from the VM instead of keeping counters
+    // in the hot path, so it is always consistent with the VM's state.
+    pub fn heap_stats(&self) -> HeapStats {
+        let mut seen = std::collections::HashSet::new();
+        let mut stats = HeapStats::default();
+
+        let values = self.stack[..self.stack_top].iter()
+            .chain(self.globals.values())
+            .chain(self.chunk.constants());
+
+        for value in values {
+            if let Value::String(string) = value {
+                // A string shared by several values is counted once.
+                if seen.insert(Rc::as_ptr(string)) {
+                    stats.strings += 1;
+                    stats.string_bytes += RC_STRING_SIZE + string.capacity();
+                }
+            }
+        }
+
+        stats
+    }
+
+    // Stops execution with a runtime error once the deadline has passed.
+    // The clock is checked every few instructions, so a script may run a
+    // little over it.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    // Stops a loaded chunk with a runtime error once it has executed
+    // this many instructions, counted across run_for calls.
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.instruction_limit = limit.unwrap_or(u64::MAX);
+    }
+
+    // Like set_deadline, but the deadline is set again each time a chunk
+    // is loaded, so every script gets the same amount of time.
+    pub fn set_time_limit(&mut self, time_limit: Option<Duration>) {
+        self.time_limit = time_limit;
+    }
+
+    // Lets natives doing slow work give up early.
+    pub fn deadline_exceeded(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // Counts the instructions executed per source line and samples the
+    // time spent on each. Turning it on starts a fresh profile; turning it
+    // off discards it.
+    pub fn enable_profiler(&mut self, enabled: bool) {
+        self.profiler = if enabled {
+            Some(Profiler::default())
+        } else {
+            None
+        };
+    }
+
+    // The lines that ran since the profiler was turned on, most
+    // expensive first. Empty when the profiler is off.
+    pub fn profile(&self) -> Vec<LineProfile> {
+        self.profiler.as_ref()
+            .map(Profiler::profile)
+            .unwrap_or_default()
+    }
+
+    // Records the last few executed instructions so runtime errors and
+    // dump_state can show them. Off by default to keep dispatch cheap.
+    pub fn set_history(&mut self, enabled: bool) {
+        self.history = enabled;
+        self.recent_offsets.clear();
+    }
+
+    // Renders the globals, the stack and, with the history turned on, the
+    // last few executed instructions, meant for looking around after a
+    // runtime error.
+    // Natives are left out of the globals and long strings are cut short.
+    pub fn dump_state(&self) -> String {
+        let mut output = String::new();
+
+        writeln!(output, "== globals ==").unwrap();
+        let mut globals: Vec<_> = self.globals.iter()
+            .filter(|(_, value)| !matches!(value, Value::Native(_) | Value::Closure(_)))
+            .collect();
+        globals.sort_by(|a, b| a.0.cmp(b.0));
+
+        for (name, value) in globals {
+            writeln!(output, "{} = {}", name, Self::dump_value(value)).unwrap();
+        }
+
+        writeln!(output, "== stack ==").unwrap();
+        for (slot, value) in self.stack[..self.stack_top].iter().enumerate() {
+            match u8::try_from(slot).ok().and_then(|slot| self.chunk.local_name(slot, self.ip)) {
+                Some(name) => writeln!(output, "{:4} {} ; {}", slot, Self::dump_value(value), name).unwrap(),
+                None => writeln!(output, "{:4} {}", slot, Self::dump_value(value)).unwrap()
+            }
+        }
+
+        writeln!(output, "== recent instructions ==").unwrap();
+        for instruction in self.recent_instructions() {
+            writeln!(output, "{}", disassemble_instruction(&instruction, false)).unwrap();
+        }
+
+        output
+    }
+
+    fn recent_instructions(&self) -> Vec<DecodedInstruction> {
+        if self.recent_offsets.is_empty() {
+            return Vec::new()
+        }
+
+        let decoded: Vec<_> = self.chunk.instructions().collect();
+        self.recent_offsets.iter()
+            .filter_map(|offset| decoded.iter().find(|decoded| decoded.offset == *offset).cloned())
+            .collect()
+    }
+
+    fn dump_value(value: &Value) -> String {
+        let text = value.repr();
+
+        if text.chars().count() <= DUMP_STRING_LIMIT {
+            return text
+        }
+
+        format!("{}...", text.chars().take(DUMP_STRING_LIMIT).collect::<String>())
     }
 
-    fn read_byte(&mut self) -> Byte {
-        let opcode = self.chunk.get_byte(self.ip);
-        self.ip += 1;
-        opcode
+    // The number of instructions executed by the last call to interpret.
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
     }
 
-    fn read_constant(&mut self) -> Value {
-        let byte = self.read_byte();
-        self.chunk.get_value(byte.byte)
+    fn read_byte(&mut self) -> RuntimeResult<Byte> {
+        match self.chunk.try_get_byte(self.ip) {
+            Some(byte) => {
+                let byte = byte.clone();
+                self.ip += 1;
+                Ok(byte)
+            },
+            None => self.error(codes::MALFORMED_BYTECODE, Error::from("Malformed bytecode: unexpected end of chunk."))
+        }
+    }
+
+    fn read_local_slot(&mut self) -> RuntimeResult<usize> {
+        let slot = self.read_byte()?.byte as usize;
+
+        if slot >= self.stack_top {
+            return self.error(codes::MALFORMED_BYTECODE, format!("Malformed bytecode: local slot {} is past the top of the stack.", slot));
+        }
+
+        Ok(slot)
+    }
+
+    fn read_constant(&mut self) -> RuntimeResult<Value> {
+        let byte = self.read_byte()?;
+
+        match self.chunk.try_get_value(byte.byte) {
+            Some(value) => Ok(value.clone()),
+            None => self.error(codes::MALFORMED_BYTECODE, format!("Malformed bytecode: constant {} does not exist.", byte.byte))
+        }
     }
     
-    fn peek(&mut self, distance: usize) -> Value {
-        self.stack[self.stack_top - 1 - distance].clone()
+    // Chunks built by hand needn't have passed verify_stack, so an
+    // instruction that would read below the bottom of the stack is an
+    // error instead of a panic.
+    fn require_stack(&mut self, count: usize) -> RuntimeResult<()> {
+        if count > self.stack_top {
+            return self.error(codes::MALFORMED_BYTECODE, format!("Malformed bytecode: stack underflow, {} needed but {} on the stack.", count, self.stack_top))
+        }
+
+        Ok(())
     }
 
-    fn is_falsey(&mut self, value: Value) -> bool {
-        value.is_nil() || (value.is_bool() && !value.as_bool())
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack_top - 1 - distance]
     }
 
-    fn concat(&mut self) {
+    // Replaces the top two values with the result of a binary operation
+    // without cloning either operand.
+    fn replace_operands(&mut self, result: Value) {
+        self.stack_top -= 1;
+        self.stack[self.stack_top - 1] = result;
+    }
+
+    fn is_falsey(&self, value: &Value) -> bool {
+        !value.is_truthy()
+    }
+
+    fn concat(&mut self) -> RuntimeResult<()> {
         let b = self.pop_value().as_string();
         let a = self.pop_value().as_string();
+
+        if a.len() + b.len() > self.limits.string_length {
+            return self.error(codes::RUNTIME_LIMIT, format!("Resulting string is too long (limit {} bytes).", self.limits.string_length));
+        }
+
         let concat = format!("{}{}", a, b);
-        self.push_value(Value::String(concat));
+        self.push_value(Value::String(concat.into()));
+        Ok(())
+    }
+
+    fn read_short(&mut self) -> RuntimeResult<u16> {
+        let high = self.read_byte()?.byte;
+        let low = self.read_byte()?.byte;
+        Ok(u16::from_be_bytes([high, low]))
+    }
+
+    fn read_string(&mut self) -> RuntimeResult<Rc<String>> {
+        match self.read_constant()? {
+            Value::String(string) => Ok(string),
+            other => self.error(codes::MALFORMED_BYTECODE, format!("Malformed bytecode: expected a name but found {}.", other.type_name()))
+        }
+    }
+
+    fn error<T>(&mut self, code: &ErrorCode, message: Error) -> RuntimeResult<T> {
+        self.raise(Some(code.code), message)
+    }
+
+    // Errors from the host's own callbacks have no code.
+    fn host_error<T>(&mut self, message: Error) -> RuntimeResult<T> {
+        self.raise(None, message)
+    }
+
+    fn native_error<T>(&mut self, error: NativeError) -> RuntimeResult<T> {
+        self.raise(error.code, error.message)
+    }
+
+    fn raise<T>(&mut self, code: Option<&'static str>, message: Error) -> RuntimeResult<T> {
+        let mut error = RuntimeError::new(message, self.current_instruction.line, self.stack_trace());
+        error.code = code;
+
+        error.history = self.recent_instructions().iter()
+            .map(|decoded| HistoryEntry::new(decoded.offset, decoded.instruction.name(), decoded.line))
+            .collect();
+
+        Err(error)
+    }
+
+    fn undefined_variable(&self, name: &str) -> Error {
+        if let Some(line) = self.chunk.later_definition(name) {
+            return format!("Undefined variable {} (defined later at line {}; was it executed yet?)", name, line)
+        }
+
+        match self.globals.closest(name) {
+            Some(suggestion) => format!("Undefined variable {}; did you mean '{}'?", name, suggestion),
+            None => format!("Undefined variable {}", name)
+        }
+    }
+
+    fn write_output(&mut self, text: &str) -> RuntimeResult<()> {
+        let result = self.output.write(text);
+        self.output_result(result)
+    }
+
+    fn flush_output(&mut self) -> RuntimeResult<()> {
+        let result = self.output.flush();
+        self.output_result(result)
+    }
+
+    fn output_result(&mut self, result: ErrorResult<()>) -> RuntimeResult<()> {
+        match result {
+            Err(error) if self.print_error_policy == PrintErrorPolicy::Abort => {
+                self.error(codes::OUTPUT_FAILED, format!("Could not print: {}", error))
+            },
+            _ => Ok(())
+        }
+    }
+
+    fn stack_trace(&self) -> Vec<TraceFrame> {
+        vec![TraceFrame::new(self.chunk.name(), None)]
+    }
+
+    fn notify_watchers(&mut self, name: &str, value: &Value) -> RuntimeResult<()> {
+        if self.watchers.is_empty() {
+            return Ok(())
+        }
+
+        let old_value = self.globals.get(name).cloned();
+        let line = self.current_instruction.line;
+        let mut result = Ok(());
+
+        for watcher in self.watchers.iter_mut().filter(|watcher| watcher.name == name) {
+            result = (watcher.callback)(old_value.as_ref(), value, line);
+
+            if result.is_err() {
+                break;
+            }
+        }
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(error) => self.host_error(error)
+        }
     }
 
-    fn read_string(&mut self) -> String {
-        self.read_constant().to_string()
+    fn indexed_string(&mut self, target: &Value) -> RuntimeResult<Rc<String>> {
+        match target {
+            Value::String(string) => Ok(string.clone()),
+            other => self.error(codes::INVALID_INDEX, format!("Can't index a {}.", other.type_name()))
+        }
     }
 
-    #[allow(unused_must_use)]
-    fn error(&mut self, message: Error) -> ErrorResult<()> {
-        let mut error_string = Error::new();
-        error_string.write_str(format!("[line {}] Error: ", self.current_instruction.line).as_str());
-        error_string.write_str(message.as_str());
+    // Turns a character index into a position from the start. Negative
+    // indices count back from the end, so -1 is the last character. Slice
+    // bounds may also be the length itself.
+    fn string_position(index: &Value, length: usize, is_bound: bool) -> ErrorResult<usize> {
+        let index = match index {
+            Value::Number(number) if number.fract() == 0.0 => *number,
+            Value::Number(number) => return Err(format!("String index must be a whole number, not {}.", number)),
+            other => return Err(format!("String index must be a number, not a {}.", other.type_name()))
+        };
+
+        let position = if index < 0.0 { length as f64 + index } else { index };
+        let limit = if is_bound { length } else { length.saturating_sub(1) };
+
+        if position < 0.0 || position > limit as f64 || (!is_bound && length == 0) {
+            return Err(format!("String index {} out of range for length {}.", index, length));
+        }
+
+        Ok(position as usize)
+    }
 
-        Err(error_string)
+    // Calls a native or native closure from the host, for example one a
+    // script stored in a global. Only meant for use between runs, when
+    // no script is executing.
+    pub fn call_value(&mut self, callee: &Value, args: &[Value]) -> NativeResult<Value> {
+        match callee {
+            Value::Native(native) => (native.function)(self, args),
+            Value::Closure(closure) => (closure.function)(args).map_err(NativeError::from),
+            other => Err(codes::NOT_CALLABLE.error(format!("Can't call a {}.", other.type_name())))
+        }
     }
 
-    pub fn run(&mut self) -> ErrorResult<()> {
+    fn call_stack_value(&mut self, arg_count: usize) -> RuntimeResult<()> {
+        let callee = self.peek(arg_count).clone();
+        if !matches!(callee, Value::Native(_) | Value::Closure(_)) {
+            return self.error(codes::NOT_CALLABLE, Error::from("Can only call functions."))
+        }
+
+        let args_start = self.stack_top - arg_count;
+        let args = self.stack[args_start..self.stack_top].to_vec();
+        let result = self.call_value(&callee, &args);
+        self.stack_top = args_start - 1;
+
+        match result {
+            Ok(value) => {
+                self.push_value(value);
+                Ok(())
+            },
+            Err(error) => self.native_error(error)
+        }
+    }
+
+    // Waits out a suspension when the host runs the script to the end.
+    fn sleep(&mut self, wait: Duration) -> RuntimeResult<()> {
+        if self.deadline.is_some_and(|deadline| Instant::now() + wait > deadline) {
+            return self.error(codes::RUNTIME_LIMIT, Error::from("Execution time limit exceeded."))
+        }
+
+        self.flush_output()?;
+        std::thread::sleep(wait);
+        Ok(())
+    }
+
+    fn invoke(&mut self, name: &str, arg_count: usize) -> RuntimeResult<()> {
+        let receiver = self.peek(arg_count);
+
+        let method = match find_method(receiver, name) {
+            Some(method) => method,
+            None => {
+                let type_name = receiver.type_name();
+                return self.error(codes::NOT_CALLABLE, format!("Type {} has no method '{}'.", type_name, name))
+            }
+        };
+
+        let args_start = self.stack_top - arg_count - 1;
+        let result = method(&self.stack[args_start..self.stack_top]);
+        self.stack_top = args_start;
+
+        match result {
+            Ok(value) => {
+                self.push_value(value);
+                Ok(())
+            },
+            Err(error) => self.native_error(error)
+        }
+    }
+
+    pub fn run(&mut self) -> RuntimeResult<()> {
+        let result = self.execute(None);
+
+        if let Err(error) = &result {
+            self.unwind_defers(error);
+        }
+
+        result.map(|_| ())
+    }
+
+    fn execute(&mut self, budget: Option<u64>) -> RuntimeResult<RunStatus> {
+        let mut executed = 0;
+
         macro_rules! binop {
             ($value_type: ident, $op: tt) => {{
-                if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                    return self.error(Error::from("Operands must be numbers."))
-                }
+                let result = match (self.peek(1), self.peek(0)) {
+                    (Value::Number(a), Value::Number(b)) => Value::$value_type(*a $op *b),
+                    _ => return self.error(codes::OPERAND_TYPE, Error::from("Operands must be numbers."))
+                };
 
-                let b = self.pop_value().as_number();
-                let a = self.pop_value().as_number();
-                self.push_value(Value::$value_type(a $op b))
+                self.replace_operands(result);
             }}
         }
 
         loop {
-            self.current_instruction = self.read_byte();
+            if budget.is_some_and(|budget| executed >= budget) {
+                return Ok(RunStatus::Paused)
+            }
+            executed += 1;
+
+            if self.history {
+                if self.recent_offsets.len() == RECENT_INSTRUCTIONS {
+                    self.recent_offsets.pop_front();
+                }
+                self.recent_offsets.push_back(self.ip);
+            }
+
+            self.current_instruction = self.read_byte()?;
+            self.instruction_count += 1;
+
+            if self.instruction_count > self.instruction_limit {
+                return self.error(codes::RUNTIME_LIMIT, format!("Instruction limit exceeded (limit {}).", self.instruction_limit))
+            }
+
+            if let Some(profiler) = &mut self.profiler {
+                profiler.record(self.current_instruction.line);
+            }
+
+            let line = self.current_instruction.line;
+            if self.hook_line != Some(line) {
+                if let Some(line_hook) = &mut self.line_hook {
+                    self.hook_line = Some(line);
+
+                    if let Err(error) = line_hook(line) {
+                        return self.host_error(error)
+                    }
+                }
+            }
+
+            if self.stack_top > self.limits.stack {
+                return self.error(codes::RUNTIME_LIMIT, format!("Stack overflow (limit {} values).", self.limits.stack))
+            }
+
+            if self.instruction_count.is_multiple_of(DEADLINE_CHECK_INTERVAL) && self.deadline_exceeded() {
+                return self.error(codes::RUNTIME_LIMIT, Error::from("Execution time limit exceeded."))
+            }
 
-            match OpCode::try_from(self.current_instruction.byte).unwrap() {
+            let opcode = match OpCode::try_from(self.current_instruction.byte) {
+                Ok(opcode) => opcode,
+                Err(_) => return self.error(codes::MALFORMED_BYTECODE, format!("Malformed bytecode: unknown opcode {}.", self.current_instruction.byte))
+            };
+
+            self.require_stack(opcode.info().pops as usize)?;
+
+            match opcode {
                 OpCode::Return => {
-                    break;
+                    // Stay on the Return so resuming a finished chunk
+                    // reports Done again.
+                    self.ip -= 1;
+                    return Ok(RunStatus::Done)
                 },
                 OpCode::Constant => {
-                    let constant = self.read_constant();
+                    let constant = self.read_constant()?;
                     self.push_value(constant);
                 },
                 OpCode::Negate => {
-                    if !self.peek(0).is_number() {
-                        return self.error(Error::from("Operand(s) must be a number."))
+                    let top = self.stack_top - 1;
+                    match &mut self.stack[top] {
+                        Value::Number(number) => *number = -*number,
+                        _ => return self.error(codes::OPERAND_TYPE, Error::from("Operand(s) must be a number."))
                     }
-
-                    let value = self.pop_value();
-                    self.push_value(Value::Number(-value.as_number()))
                 },
                 OpCode::Add => {
-                    if self.peek(0).is_string() && self.peek(1).is_string() {
-                        self.concat();
-                    } else if self.peek(0).is_number() && self.peek(1).is_number() {
-                        let b = self.pop_value().as_number();
-                        let a = self.pop_value().as_number();
-                        self.push_value(Value::Number(a + b));
-                    } else {
-                        return self.error(Error::from("Invalid operands."))
+                    match (self.peek(1), self.peek(0)) {
+                        (Value::Number(a), Value::Number(b)) => {
+                            let sum = Value::Number(a + b);
+                            self.replace_operands(sum);
+                        },
+                        (Value::String(_), Value::String(_)) => self.concat()?,
+                        _ => return self.error(codes::OPERAND_TYPE, Error::from("Invalid operands."))
                     }
                 },
                 OpCode::Subtract => binop!(Number, -),
@@ -137,48 +1120,265 @@ impl VM {
                 OpCode::True => self.push_value(Value::Bool(true)),
                 OpCode::False => self.push_value(Value::Bool(false)),
                 OpCode::Not => {
-                    let popped = self.pop_value();
-                    let is_falsey = self.is_falsey(popped);
-                    self.push_value(Value::Bool(is_falsey))
+                    let is_falsey = Value::Bool(self.is_falsey(self.peek(0)));
+                    self.stack[self.stack_top - 1] = is_falsey;
                 },
                 OpCode::Equal => {
-                    let b = self.pop_value();
-                    let a = self.pop_value();
-                    self.push_value(Value::Bool(a == b));
+                    let equal = Value::Bool(self.peek(1) == self.peek(0));
+                    self.replace_operands(equal);
+                },
+                OpCode::NotEqual => {
+                    let not_equal = Value::Bool(self.peek(1) != self.peek(0));
+                    self.replace_operands(not_equal);
                 },
                 OpCode::Greater => binop!(Bool, >),
                 OpCode::Less => binop!(Bool, <),
+                // Older bytecode spells these as Less+Not and Greater+Not,
+                // which still runs but makes `NaN >= x` true.
+                OpCode::GreaterEqual => binop!(Bool, >=),
+                OpCode::LessEqual => binop!(Bool, <=),
                 OpCode::Print => {
                     let popped = self.pop_value();
-                    self.print_fn.clone()(format!("{}\n", popped))?;
+                    self.write_output(&format!("{}\n", popped))?;
                 },
                 OpCode::Pop => {
                     self.pop_value();
                 },
                 OpCode::DefineGlobal => {
-                    let name = self.read_string();
-                    let value = self.peek(0);
-                    self.globals.insert(name, value);
-                    self.pop_value();
+                    let name = self.read_string()?;
+                    let value = self.pop_value();
+                    self.notify_watchers(&name, &value)?;
+                    self.globals.insert(&name, value);
+
+                    if let Some((_, defined)) = &mut self.recording {
+                        defined.insert(name.to_string());
+                    }
                 },
                 OpCode::GetGlobal => {
-                    let name = self.read_string();
-                    if !self.globals.contains_key(&name) {
-                        return self.error(format!("Undefined variable {}", name));
+                    let name = self.read_string()?;
+                    match self.globals.get(&name) {
+                        Some(value) => self.push_value(value.clone()),
+                        None => return self.error(codes::UNDEFINED_VARIABLE, self.undefined_variable(&name))
                     }
-                    let value = self.globals.get(&name).unwrap();
-                    self.push_value(value.clone());
                 },
                 OpCode::SetGlobal => {
-                    let name = self.read_string();
+                    let name = self.read_string()?;
                     if !self.globals.contains_key(&name) {
-                        return self.error(format!("Undefined variable {}", name));
+                        return self.error(codes::UNDEFINED_VARIABLE, self.undefined_variable(&name));
                     }
-                    *self.globals.get_mut(&name).unwrap() = self.peek(0)
+                    let value = self.peek(0).clone();
+                    self.notify_watchers(&name, &value)?;
+                    *self.globals.get_mut(&name).unwrap() = value
+                },
+                OpCode::Invoke => {
+                    let name = self.read_string()?;
+                    let arg_count = self.read_byte()?.byte as usize;
+                    self.require_stack(arg_count + 1)?;
+                    self.invoke(&name, arg_count)?;
+                },
+                OpCode::Call => {
+                    let arg_count = self.read_byte()?.byte as usize;
+                    self.require_stack(arg_count + 1)?;
+                    self.call_stack_value(arg_count)?;
+
+                    if let Some(wait) = self.pending_wait.take() {
+                        if budget.is_some() {
+                            return Ok(RunStatus::Waiting(wait))
+                        }
+
+                        self.sleep(wait)?;
+                    }
+                },
+                OpCode::GetLocal => {
+                    let slot = self.read_local_slot()?;
+                    self.push_value(self.stack[slot].clone());
+                },
+                OpCode::SetLocal => {
+                    let slot = self.read_local_slot()?;
+                    self.stack[slot] = self.peek(0).clone();
+                },
+                OpCode::Jump => {
+                    let offset = self.read_short()? as usize;
+                    self.ip += offset;
+                },
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short()? as usize;
+                    if self.is_falsey(self.peek(0)) {
+                        self.ip += offset;
+                    }
+                },
+                OpCode::JumpIfNil => {
+                    let offset = self.read_short()? as usize;
+                    if self.peek(0).is_nil() {
+                        self.ip += offset;
+                    }
+                },
+                OpCode::Loop => {
+                    let offset = self.read_short()? as usize;
+                    self.ip -= offset;
+                },
+                OpCode::Defer => {
+                    let offset = self.read_short()? as usize;
+                    self.defers.push(PendingDefer {
+                        body: self.ip,
+                        stack_top: self.stack_top
+                    });
+                    self.ip += offset;
+                },
+                OpCode::RunDefer => {
+                    let deferred = match self.defers.pop() {
+                        Some(deferred) => deferred,
+                        None => return self.error(codes::MALFORMED_BYTECODE, Error::from("Malformed bytecode: no deferred statement to run."))
+                    };
+
+                    self.defer_returns.push(self.ip);
+                    self.ip = deferred.body;
+                },
+                OpCode::EndDefer => match self.defer_returns.pop() {
+                    Some(UNWINDING) => return Ok(RunStatus::Done),
+                    Some(ip) => self.ip = ip,
+                    None => return self.error(codes::MALFORMED_BYTECODE, Error::from("Malformed bytecode: END_DEFER outside a deferred statement."))
+                },
+                OpCode::CheckRange => {
+                    let (start, end) = match (self.peek(1), self.peek(0)) {
+                        (Value::Number(start), Value::Number(end))
+                            if start.fract() == 0.0 && end.fract() == 0.0 => (*start, *end),
+                        _ => return self.error(codes::INVALID_RANGE, Error::from("Range bounds must be whole numbers."))
+                    };
+
+                    if start > end {
+                        return self.error(codes::INVALID_RANGE, format!("Range start {} is greater than its end {}.", start, end));
+                    }
+
+                    // Past 2^53 adding 1 no longer changes a number, so the
+                    // counter would never reach the end.
+                    if start.abs().max(end.abs()) > MAX_RANGE_BOUND {
+                        return self.error(codes::INVALID_RANGE, format!("Range bounds must be between -{0} and {0}.", MAX_RANGE_BOUND));
+                    }
+                },
+                OpCode::PopN => {
+                    let count = self.read_byte()?.byte as usize;
+
+                    if count > self.stack_top {
+                        return self.error(codes::MALFORMED_BYTECODE, format!("Malformed bytecode: can't pop {} values from a stack of {}.", count, self.stack_top))
+                    }
+
+                    self.stack_top -= count;
+                },
+                OpCode::IterNext => {
+                    let slot = self.read_byte()?.byte as usize;
+
+                    // Strings are the only iterable values so far. The
+                    // string and a byte offset into it sit in two hidden
+                    // locals the script can't name, so assigning to the loop
+                    // variable in the body doesn't change what comes next,
+                    // and strings can't be mutated. Hand-written bytecode
+                    // could still put anything in those slots, so they are
+                    // checked. Once exhausted, nil stands in for the item.
+                    if slot + 1 >= self.stack_top {
+                        return self.error(codes::MALFORMED_BYTECODE, format!("Malformed bytecode: local slot {} is past the top of the stack.", slot + 1));
+                    }
+
+                    let string = match &self.stack[slot] {
+                        Value::String(string) => string.clone(),
+                        other => return self.error(codes::INVALID_RANGE, format!("Can't iterate over a {}.", other.type_name()))
+                    };
+                    let index = match &self.stack[slot + 1] {
+                        Value::Number(index) => *index as usize,
+                        other => return self.error(codes::MALFORMED_BYTECODE, format!("Malformed bytecode: iterator index is a {}.", other.type_name()))
+                    };
+
+                    let rest = match string.get(index..) {
+                        Some(rest) => rest,
+                        None => return self.error(codes::MALFORMED_BYTECODE, format!("Malformed bytecode: iterator index {} is not a character boundary.", index))
+                    };
+
+                    match rest.chars().next() {
+                        Some(character) => {
+                            self.stack[slot + 1] = Value::Number((index + character.len_utf8()) as f64);
+                            self.push_value(Value::String(character.to_string().into()));
+                            self.push_value(Value::Bool(true));
+                        },
+                        None => {
+                            self.push_value(Value::Nil);
+                            self.push_value(Value::Bool(false));
+                        }
+                    }
+                },
+                OpCode::In => {
+                    let container = self.pop_value();
+                    let item = self.pop_value();
+
+                    let found = match (&item, &container) {
+                        (Value::String(item), Value::String(container)) => container.contains(&**item),
+                        (_, Value::String(_)) => {
+                            return self.error(codes::OPERAND_TYPE, format!("Can't search a string for a {}.", item.type_name()))
+                        },
+                        _ => {
+                            return self.error(codes::OPERAND_TYPE, format!("Right operand of 'in' can't be a {}.", container.type_name()))
+                        }
+                    };
+
+                    self.push_value(Value::Bool(found));
+                },
+                OpCode::Index => {
+                    let index = self.pop_value();
+                    let target = self.pop_value();
+                    let string = self.indexed_string(&target)?;
+
+                    // Indices count characters rather than bytes, so this
+                    // walks the string each time.
+                    let length = string.chars().count();
+                    let position = match Self::string_position(&index, length, false) {
+                        Ok(position) => position,
+                        Err(error) => return self.error(codes::INVALID_INDEX, error)
+                    };
+
+                    let character = string.chars().nth(position).unwrap();
+                    self.push_value(Value::String(character.to_string().into()));
+                },
+                OpCode::Slice => {
+                    let end = self.pop_value();
+                    let start = self.pop_value();
+                    let target = self.pop_value();
+                    let string = self.indexed_string(&target)?;
+
+                    let length = string.chars().count();
+                    let (start, end) = match (
+                        Self::string_position(&start, length, true),
+                        Self::string_position(&end, length, true)
+                    ) {
+                        (Ok(start), Ok(end)) if start <= end => (start, end),
+                        (Ok(start), Ok(end)) => {
+                            return self.error(codes::INVALID_INDEX, format!("Slice start {} is after its end {}.", start, end))
+                        },
+                        (Err(error), _) | (_, Err(error)) => return self.error(codes::INVALID_INDEX, error)
+                    };
+
+                    let byte_offset = |position| string.char_indices()
+                        .nth(position)
+                        .map_or(string.len(), |(offset, _)| offset);
+                    let slice = &string[byte_offset(start)..byte_offset(end)];
+                    self.push_value(Value::String(slice.to_string().into()));
+                },
+                OpCode::SetIndex => {
+                    self.pop_value();
+                    self.pop_value();
+                    let target = self.pop_value();
+                    self.indexed_string(&target)?;
+
+                    return self.error(codes::INVALID_INDEX, Error::from("Strings can't be changed; build a new string instead."))
+                },
+                OpCode::Dup => {
+                    self.push_value(self.peek(0).clone());
+                },
+                OpCode::Zero => self.push_value(Value::Number(0.0)),
+                OpCode::One => self.push_value(Value::Number(1.0)),
+                OpCode::SmallInt => {
+                    let value = self.read_byte()?.byte;
+                    self.push_value(Value::Number(value as f64));
                 }
             }
         }
-
-        Ok(())
     }
-}
\ No newline at end of file
+}